@@ -0,0 +1,66 @@
+use png::ColorMode;
+use png::Decoder;
+use png::Encoder;
+use png::InterlaceMethod;
+use png::Result;
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Cursor;
+use std::io::Read;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 20;
+// Size of the images generated when no files are given
+const GENERATED_SIZE: u32 = 1024;
+
+// Generates an RGB image of gradients with some noise, which compresses about as well as a photograph
+fn generate_image(interlace_method: InterlaceMethod) -> Result<Vec<u8>> {
+    let pitch = GENERATED_SIZE as usize * 3;
+    let mut samples = vec![0; pitch * GENERATED_SIZE as usize];
+    let mut state: u32 = 1;
+    for y in 0 .. GENERATED_SIZE as usize {
+        for x in 0 .. GENERATED_SIZE as usize {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = (state >> 29) as usize;
+            let i = y * pitch + x * 3;
+            samples[i] = ((x + noise) / 4) as u8;
+            samples[i + 1] = ((y + noise) / 4) as u8;
+            samples[i + 2] = ((x + y) / 8 + noise) as u8;
+        }
+    }
+    let mut encoder = Encoder::new(GENERATED_SIZE, GENERATED_SIZE, ColorMode::RGB8)?;
+    encoder.set_interlace_method(interlace_method);
+    encoder.encode(Vec::new(), &samples, pitch)
+}
+
+// Returns the average time it takes to decode the file opened by the function
+fn measure<R, F>(mut open: F) -> Result<f64> where R: Read, F: FnMut() -> Result<R> {
+    let start = Instant::now();
+    for _ in 0 .. ITERATIONS {
+        let decoder = Decoder::new(BufReader::new(open()?))?;
+        let pitch = decoder.info().width as usize * 4;
+        let mut pixels = vec![0; pitch * decoder.info().height as usize].into_boxed_slice();
+        decoder.decode(&mut pixels, pitch)?;
+    }
+    Ok((start.elapsed() / ITERATIONS).as_secs_f64() * 1000.0)
+}
+
+// Measures the average time it takes to decode each of the files given as arguments,
+// or generated images if there are none:
+// cargo bench --bench decode -- [<file>...]
+fn main() -> Result<()> {
+    let filenames: Vec<String> = env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect();
+    if filenames.is_empty() {
+        for &(name, interlace_method) in &[("generated", InterlaceMethod::NoInterlace), ("generated, Adam7", InterlaceMethod::Adam7)] {
+            let png = generate_image(interlace_method)?;
+            println!("{}: {:.3} ms", name, measure(|| Ok(Cursor::new(&png)))?);
+        }
+    }
+    for filename in filenames {
+        println!("{}: {:.3} ms", filename, measure(|| Ok(File::open(&filename)?))?);
+    }
+    Ok(())
+}
@@ -0,0 +1,503 @@
+use crate::chunk::ChunkReader;
+use crate::chunk::CrcPolicy;
+use crate::decoder;
+use crate::decoder::ConversionTables;
+use crate::decoder::ImageInfo;
+use crate::file::ByteReader;
+use crate::idat::IdatReader;
+use crate::Error;
+use crate::Result;
+use std::io::Read;
+use std::mem;
+
+// Animation information from an acTL chunk. Zero plays means the animation loops forever.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AnimationControl {
+    pub num_frames: u32,
+    pub num_plays: u32,
+}
+
+// What happens to the region of a frame before the next one is rendered
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DisposeOp {
+    None,
+    // Cleared to fully transparent black
+    Background,
+    // Reverted to the contents from before the frame was rendered
+    Previous,
+}
+
+// How a frame is combined with the contents of its region
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlendOp {
+    Source,
+    Over,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FrameControl {
+    pub sequence_number: u32,
+    pub width: u32,
+    pub height: u32,
+    pub x_offset: u32,
+    pub y_offset: u32,
+    pub delay_num: u16,
+    pub delay_den: u16,
+    pub dispose_op: DisposeOp,
+    pub blend_op: BlendOp,
+}
+
+impl FrameControl {
+    // Time to display the frame for in seconds
+    pub fn delay(&self) -> f64 {
+        let den = if self.delay_den == 0 { 100 } else { self.delay_den };
+        self.delay_num as f64 / den as f64
+    }
+}
+
+// A frame of the animation composited onto the full image, as 8-bit RGBA pixels without padding between rows
+pub struct Frame {
+    pub pixels: Box<[u8]>,
+    pub control: FrameControl,
+}
+
+// Returns None if the chunk is invalid, in which case it should be ignored
+pub fn load_actl<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<AnimationControl>> where R: Read {
+    if length != 8 {
+        warn!("acTL chunk has incorrect length");
+        return Ok(None);
+    }
+    let num_frames = chunk.read_u32()?;
+    let num_plays = chunk.read_u32()?;
+    info!("Number of frames: {}", num_frames);
+    info!("Number of plays: {}", num_plays);
+    if num_frames == 0 {
+        warn!("acTL chunk has zero frames");
+        return Ok(None);
+    }
+    Ok(Some(AnimationControl { num_frames, num_plays }))
+}
+
+pub fn load_fctl<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<FrameControl>> where R: Read {
+    if length != 26 {
+        warn!("fcTL chunk has incorrect length");
+        return Ok(None);
+    }
+    let sequence_number = chunk.read_u32()?;
+    let width = chunk.read_u32()?;
+    let height = chunk.read_u32()?;
+    let x_offset = chunk.read_u32()?;
+    let y_offset = chunk.read_u32()?;
+    let delay_num = chunk.read_u16()?;
+    let delay_den = chunk.read_u16()?;
+    let dispose_op = chunk.read_u8()?;
+    let blend_op = chunk.read_u8()?;
+    info!("Sequence number: {}", sequence_number);
+    info!("Frame size: {} x {}", width, height);
+    info!("Frame offset: {}, {}", x_offset, y_offset);
+    info!("Frame delay: {}/{}", delay_num, delay_den);
+    info!("Dispose op: {}", dispose_op);
+    info!("Blend op: {}", blend_op);
+    let dispose_op = match dispose_op {
+        0 => DisposeOp::None,
+        1 => DisposeOp::Background,
+        2 => DisposeOp::Previous,
+        _ => {
+            warn!("Invalid fcTL dispose op");
+            return Ok(None);
+        },
+    };
+    let blend_op = match blend_op {
+        0 => BlendOp::Source,
+        1 => BlendOp::Over,
+        _ => {
+            warn!("Invalid fcTL blend op");
+            return Ok(None);
+        },
+    };
+    Ok(Some(FrameControl { sequence_number, width, height, x_offset, y_offset, delay_num, delay_den, dispose_op, blend_op }))
+}
+
+// Blends a non-premultiplied RGBA pixel over another
+fn blend_over(src: &[u8], dst: &mut [u8]) {
+    let src_alpha = src[3] as u32;
+    if src_alpha == 0xFF {
+        dst.copy_from_slice(src);
+        return;
+    }
+    if src_alpha == 0 {
+        return;
+    }
+    let dst_alpha = dst[3] as u32 * (0xFF - src_alpha) / 0xFF;
+    let alpha = src_alpha + dst_alpha;
+    for i in 0 .. 3 {
+        dst[i] = ((src[i] as u32 * src_alpha + dst[i] as u32 * dst_alpha + alpha / 2) / alpha) as u8;
+    }
+    dst[3] = alpha as u8;
+}
+
+enum State<R> where R: Read {
+    // The first IDAT chunk, before the default image is decoded or skipped
+    DefaultImage(ChunkReader<R>),
+    // Between frames, positioned at the start of a chunk
+    Chunks(R),
+    Done,
+}
+
+// Iterator over the frames of an image, created by Decoder::frames.
+// Iteration stops after the first error.
+pub struct Frames<R> where R: Read {
+    info: ImageInfo,
+    tables: ConversionTables,
+    state: State<R>,
+    crc_policy: CrcPolicy,
+    canvas: Box<[u8]>,
+    // Canvas from before the last frame was rendered, kept if it has to be restored
+    saved_canvas: Option<Box<[u8]>>,
+    last_control: Option<FrameControl>,
+    next_sequence_number: u32,
+    frames_read: u32,
+}
+
+impl<R> Frames<R> where R: Read {
+    // Fails if the canvas for the full image cannot be allocated
+    pub fn new(info: ImageInfo, tables: ConversionTables, idat: ChunkReader<R>) -> Result<Frames<R>> {
+        let size = (info.width as usize).checked_mul(info.height as usize).and_then(|pixels| pixels.checked_mul(4))
+            .ok_or(Error::Format("Image is too large"))?;
+        let mut canvas = Vec::new();
+        canvas.try_reserve_exact(size).map_err(|_| Error::Format("Image is too large"))?;
+        canvas.resize(size, 0);
+        let crc_policy = idat.crc_policy();
+        let next_sequence_number = if info.default_image_frame.is_some() { 1 } else { 0 };
+        Ok(Frames {
+            info, tables, state: State::DefaultImage(idat), crc_policy, canvas: canvas.into_boxed_slice(),
+            saved_canvas: None, last_control: None, next_sequence_number, frames_read: 0,
+        })
+    }
+
+    // Image information, updated with the metadata from chunks read so far
+    pub fn info(&self) -> &ImageInfo {
+        &self.info
+    }
+
+    // Disposes of the last frame, then decodes the frame data from the reader and blends it onto the canvas
+    fn render_frame<T>(&mut self, reader: T, control: FrameControl) -> Result<(T, Frame)> where T: ByteReader {
+        if control.width == 0 || control.height == 0
+            || control.x_offset as u64 + control.width as u64 > self.info.width as u64
+            || control.y_offset as u64 + control.height as u64 > self.info.height as u64 {
+            return Err(Error::Format("Frame is outside the image"));
+        }
+        let pitch = self.info.width as usize * 4;
+        let region = |control: &FrameControl| {
+            let x0 = control.x_offset as usize * 4;
+            let x1 = x0 + control.width as usize * 4;
+            (control.y_offset as usize .. (control.y_offset + control.height) as usize).map(move |y| y * pitch + x0 .. y * pitch + x1)
+        };
+        if let Some(last_control) = self.last_control.take() {
+            match last_control.dispose_op {
+                DisposeOp::None => (),
+                DisposeOp::Background => {
+                    for row in region(&last_control) {
+                        self.canvas[row].fill(0);
+                    }
+                },
+                DisposeOp::Previous => {
+                    if let Some(saved_canvas) = self.saved_canvas.take() {
+                        for row in region(&last_control) {
+                            self.canvas[row.clone()].copy_from_slice(&saved_canvas[row]);
+                        }
+                    }
+                },
+            }
+        }
+        // Disposing of the first frame to the previous contents clears it like disposing to the background,
+        // since the canvas starts out transparent
+        if control.dispose_op == DisposeOp::Previous {
+            self.saved_canvas = Some(self.canvas.clone());
+        }
+        let frame_pitch = control.width as usize * 4;
+        let mut frame = vec![0; frame_pitch * control.height as usize];
+        let conversion = self.tables.conversion(&self.info);
+        let reader = decoder::decode_image_data(reader, &mut frame, frame_pitch, control.width, control.height, &self.info, &conversion, |_| Ok(()))?;
+        for (row, frame_row) in region(&control).zip(frame.chunks(frame_pitch)) {
+            match control.blend_op {
+                BlendOp::Source => self.canvas[row].copy_from_slice(frame_row),
+                BlendOp::Over => {
+                    for (dst, src) in self.canvas[row].chunks_mut(4).zip(frame_row.chunks(4)) {
+                        blend_over(src, dst);
+                    }
+                },
+            }
+        }
+        self.last_control = Some(control);
+        self.frames_read += 1;
+        Ok((reader, Frame { pixels: self.canvas.clone(), control }))
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Frame>> {
+        let mut in_idat = false;
+        let mut file = match mem::replace(&mut self.state, State::Done) {
+            State::DefaultImage(idat) => {
+                let control = match (self.info.animation, self.info.default_image_frame) {
+                    (Some(_), Some(control)) => Some(control),
+                    (Some(_), None) => None,
+                    // A static image is a single frame covering the whole image
+                    (None, _) => Some(FrameControl {
+                        sequence_number: 0, width: self.info.width, height: self.info.height, x_offset: 0, y_offset: 0,
+                        delay_num: 0, delay_den: 0, dispose_op: DisposeOp::None, blend_op: BlendOp::Source,
+                    }),
+                };
+                match control {
+                    Some(control) => {
+                        if control.x_offset != 0 || control.y_offset != 0 || control.width != self.info.width || control.height != self.info.height {
+                            return Err(Error::Format("Default image frame does not cover the whole image"));
+                        }
+                        let (reader, frame) = self.render_frame(IdatReader::new(idat)?, control)?;
+                        self.state = State::Chunks(reader.end()?.end()?);
+                        return Ok(Some(frame));
+                    },
+                    None => {
+                        in_idat = true;
+                        idat.end()?
+                    },
+                }
+            },
+            State::Chunks(file) => file,
+            State::Done => return Ok(None),
+        };
+        loop {
+            let (mut chunk, length, chunk_type) = ChunkReader::new(file, self.crc_policy)?;
+            if chunk_type != *b"IDAT" {
+                in_idat = false;
+            }
+            match &chunk_type {
+                b"fcTL" if self.info.animation.is_some() => {
+                    let control = load_fctl(&mut chunk, length)?.ok_or(Error::Format("Invalid fcTL chunk"))?;
+                    if control.sequence_number != self.next_sequence_number {
+                        warn!("Incorrect APNG sequence number");
+                    }
+                    let (chunk, _, chunk_type) = ChunkReader::new(chunk.end()?, self.crc_policy)?;
+                    if chunk_type != *b"fdAT" {
+                        return Err(Error::Format("fcTL chunk not followed by fdAT chunk"));
+                    }
+                    let (reader, frame) = self.render_frame(IdatReader::new_fdat(chunk, control.sequence_number.wrapping_add(1))?, control)?;
+                    self.next_sequence_number = reader.next_sequence_number().unwrap_or(0);
+                    self.state = State::Chunks(reader.end()?.end()?);
+                    return Ok(Some(frame));
+                },
+                b"fdAT" if self.info.animation.is_some() => {
+                    warn!("fdAT chunk without fcTL chunk");
+                },
+                _ => {
+                    chunk = decoder::read_trailing_chunk(chunk, length, &chunk_type, &mut self.info, in_idat)?;
+                },
+            }
+            file = chunk.end()?;
+            if chunk_type == *b"IEND" {
+                if let Some(animation) = self.info.animation {
+                    if animation.num_frames != self.frames_read {
+                        warn!("Number of frames does not match acTL chunk");
+                    }
+                }
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl<R> Iterator for Frames<R> where R: Read {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Result<Frame>> {
+        self.next_frame().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::chunk_bytes;
+    use crate::decoder::PNG_SIG;
+    use crate::deflate;
+    use crate::Decoder;
+    use std::io::Cursor;
+
+    const SIZE: u32 = 4;
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const GREEN: [u8; 4] = [0, 255, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    // A frame filled with a single RGBA color
+    struct TestFrame {
+        x_offset: u32,
+        y_offset: u32,
+        width: u32,
+        height: u32,
+        dispose_op: u8,
+        blend_op: u8,
+        color: [u8; 4],
+    }
+
+    fn frame(x_offset: u32, y_offset: u32, width: u32, height: u32, dispose_op: DisposeOp, blend_op: BlendOp, color: [u8; 4]) -> TestFrame {
+        TestFrame { x_offset, y_offset, width, height, dispose_op: dispose_op as u8, blend_op: blend_op as u8, color }
+    }
+
+    // Chunks of a SIZE x SIZE RGBA animation in which the default image is the first frame
+    fn animation_chunks(frames: &[TestFrame]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut ihdr = SIZE.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&SIZE.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let mut actl = (frames.len() as u32).to_be_bytes().to_vec();
+        actl.extend_from_slice(&0u32.to_be_bytes());
+        let mut chunks = vec![(*b"IHDR", ihdr), (*b"acTL", actl)];
+        let mut sequence_number = 0u32;
+        for (i, frame) in frames.iter().enumerate() {
+            let mut fctl = sequence_number.to_be_bytes().to_vec();
+            for value in &[frame.width, frame.height, frame.x_offset, frame.y_offset] {
+                fctl.extend_from_slice(&value.to_be_bytes());
+            }
+            fctl.extend_from_slice(&[0, 1, 0, 10, frame.dispose_op, frame.blend_op]);
+            chunks.push((*b"fcTL", fctl));
+            sequence_number += 1;
+            let row: Vec<u8> = (0 .. frame.width).flat_map(|_| frame.color.to_vec()).collect();
+            let scanlines: Vec<u8> = (0 .. frame.height).flat_map(|_| [vec![0], row.clone()].concat()).collect();
+            let data = deflate::write_zlib(&scanlines, 6);
+            if i == 0 {
+                chunks.push((*b"IDAT", data));
+            } else {
+                let mut fdat = sequence_number.to_be_bytes().to_vec();
+                fdat.extend(data);
+                chunks.push((*b"fdAT", fdat));
+                sequence_number += 1;
+            }
+        }
+        chunks.push((*b"IEND", Vec::new()));
+        chunks
+    }
+
+    fn frames_from_chunks(chunks: &[([u8; 4], Vec<u8>)]) -> Frames<Cursor<Vec<u8>>> {
+        let mut png = PNG_SIG.to_vec();
+        for (chunk_type, data) in chunks {
+            png.extend(chunk_bytes(chunk_type, data));
+        }
+        Decoder::new(Cursor::new(png)).unwrap().frames().unwrap()
+    }
+
+    fn decode_frames(frames: &[TestFrame]) -> Vec<Frame> {
+        frames_from_chunks(&animation_chunks(frames)).collect::<Result<Vec<Frame>>>().unwrap()
+    }
+
+    fn pixel(frame: &Frame, x: u32, y: u32) -> [u8; 4] {
+        let i = ((y * SIZE + x) * 4) as usize;
+        [frame.pixels[i], frame.pixels[i + 1], frame.pixels[i + 2], frame.pixels[i + 3]]
+    }
+
+    // Checks every pixel of the frame, expecting the inside color within the rectangle and the outside color elsewhere
+    fn assert_rect(frame: &Frame, (x0, y0, x1, y1): (u32, u32, u32, u32), inside: [u8; 4], outside: [u8; 4]) {
+        for y in 0 .. SIZE {
+            for x in 0 .. SIZE {
+                let expected = if (x0 .. x1).contains(&x) && (y0 .. y1).contains(&y) { inside } else { outside };
+                assert_eq!(pixel(frame, x, y), expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn dispose_to_background() {
+        let frames = decode_frames(&[
+            frame(0, 0, SIZE, SIZE, DisposeOp::Background, BlendOp::Source, RED),
+            frame(1, 1, 2, 2, DisposeOp::None, BlendOp::Source, GREEN),
+        ]);
+        assert_eq!(frames.len(), 2);
+        assert_rect(&frames[0], (0, 0, SIZE, SIZE), RED, RED);
+        assert_rect(&frames[1], (1, 1, 3, 3), GREEN, CLEAR);
+    }
+
+    #[test]
+    fn dispose_to_previous() {
+        let frames = decode_frames(&[
+            frame(0, 0, SIZE, SIZE, DisposeOp::None, BlendOp::Source, RED),
+            frame(0, 0, 2, 2, DisposeOp::Previous, BlendOp::Source, GREEN),
+            frame(3, 3, 1, 1, DisposeOp::None, BlendOp::Source, BLUE),
+        ]);
+        assert_rect(&frames[1], (0, 0, 2, 2), GREEN, RED);
+        assert_rect(&frames[2], (3, 3, 4, 4), BLUE, RED);
+    }
+
+    #[test]
+    fn dispose_first_frame_to_previous() {
+        // The canvas before the first frame is transparent, so it is cleared as if disposed to the background
+        let frames = decode_frames(&[
+            frame(0, 0, SIZE, SIZE, DisposeOp::Previous, BlendOp::Source, RED),
+            frame(0, 0, 1, 1, DisposeOp::None, BlendOp::Source, BLUE),
+        ]);
+        assert_rect(&frames[0], (0, 0, SIZE, SIZE), RED, RED);
+        assert_rect(&frames[1], (0, 0, 1, 1), BLUE, CLEAR);
+    }
+
+    #[test]
+    fn blend_over() {
+        let frames = decode_frames(&[
+            frame(0, 0, SIZE, SIZE, DisposeOp::None, BlendOp::Source, RED),
+            frame(1, 1, 2, 2, DisposeOp::None, BlendOp::Over, [0, 0, 255, 128]),
+            frame(0, 0, SIZE, SIZE, DisposeOp::None, BlendOp::Over, CLEAR),
+            frame(0, 0, 2, 2, DisposeOp::None, BlendOp::Source, CLEAR),
+        ]);
+        assert_rect(&frames[1], (1, 1, 3, 3), [127, 0, 128, 255], RED);
+        // Blending transparent pixels over the canvas leaves it unchanged, while replacing it clears it
+        assert_rect(&frames[2], (1, 1, 3, 3), [127, 0, 128, 255], RED);
+        assert_eq!(pixel(&frames[3], 0, 0), CLEAR);
+        assert_eq!(pixel(&frames[3], 1, 1), CLEAR);
+        assert_eq!(pixel(&frames[3], 2, 2), [127, 0, 128, 255]);
+    }
+
+    #[test]
+    fn frame_outside_image() {
+        let chunks = animation_chunks(&[
+            frame(0, 0, SIZE, SIZE, DisposeOp::None, BlendOp::Source, RED),
+            frame(3, 3, 2, 2, DisposeOp::None, BlendOp::Source, GREEN),
+        ]);
+        let mut frames = frames_from_chunks(&chunks);
+        assert!(frames.next().unwrap().is_ok());
+        assert!(matches!(frames.next(), Some(Err(Error::Format("Frame is outside the image")))));
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn sequence_number_gap() {
+        // A gap in the sequence numbers is warned about, and the frames are decoded as usual
+        let mut chunks = animation_chunks(&[
+            frame(0, 0, SIZE, SIZE, DisposeOp::None, BlendOp::Source, RED),
+            frame(1, 1, 2, 2, DisposeOp::None, BlendOp::Source, GREEN),
+            frame(0, 0, 1, 1, DisposeOp::None, BlendOp::Source, BLUE),
+        ]);
+        for (chunk_type, data) in chunks.iter_mut() {
+            if chunk_type == b"fcTL" || chunk_type == b"fdAT" {
+                let sequence_number = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                if sequence_number >= 2 {
+                    data[.. 4].copy_from_slice(&(sequence_number + 5).to_be_bytes());
+                }
+            }
+        }
+        let frames = frames_from_chunks(&chunks).collect::<Result<Vec<Frame>>>().unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_rect(&frames[1], (1, 1, 3, 3), GREEN, RED);
+        assert_eq!(pixel(&frames[2], 0, 0), BLUE);
+        assert_eq!(pixel(&frames[2], 1, 1), GREEN);
+    }
+
+    #[test]
+    fn huge_image() {
+        let mut ihdr = 0x7FFFFFFFu32.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&0x7FFFFFFFu32.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let mut png = PNG_SIG.to_vec();
+        png.extend(chunk_bytes(b"IHDR", &ihdr));
+        png.extend(chunk_bytes(b"acTL", &[0, 0, 0, 1, 0, 0, 0, 0]));
+        png.extend(chunk_bytes(b"IDAT", &deflate::write_zlib(&[0], 6)));
+        png.extend(chunk_bytes(b"IEND", &[]));
+        let decoder = Decoder::new(Cursor::new(png)).unwrap();
+        assert!(matches!(decoder.frames(), Err(Error::Format("Image is too large"))));
+    }
+}
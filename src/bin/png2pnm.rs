@@ -0,0 +1,52 @@
+use png::Decoder;
+use png::Error;
+use png::Result;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+
+// Decodes a PNG file (or standard input if the input is "-") without a display
+// and writes it out as a binary PPM (dropping alpha) or PAM image, depending on the extension of the output file.
+fn main() -> Result<()> {
+    let mut args = env::args();
+    if args.len() != 3 {
+        return Err(Error::Format("Invalid number of arguments"));
+    }
+    let input = args.nth(1).unwrap();
+    let output = args.next().unwrap();
+    let with_alpha = if output.ends_with(".ppm") {
+        false
+    } else if output.ends_with(".pam") {
+        true
+    } else {
+        return Err(Error::Format("Output file extension is not .ppm or .pam"));
+    };
+
+    let file: Box<dyn Read> = if input == "-" { Box::new(io::stdin()) } else { Box::new(File::open(&input)?) };
+    let decoder = Decoder::new(BufReader::new(file))?;
+    let width = decoder.info().width;
+    let height = decoder.info().height;
+    let pitch = width as usize * 4;
+    let size = pitch.checked_mul(height as usize).ok_or(Error::Format("Image is too large"))?;
+    let mut pixels = Vec::new();
+    pixels.try_reserve_exact(size).map_err(|_| Error::Format("Image is too large"))?;
+    pixels.resize(size, 0);
+    decoder.decode(&mut pixels, pitch)?;
+
+    let mut out = BufWriter::new(File::create(&output)?);
+    if with_alpha {
+        write!(out, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n", width, height)?;
+        out.write_all(&pixels)?;
+    } else {
+        write!(out, "P6\n{} {}\n255\n", width, height)?;
+        for pixel in pixels.chunks(4) {
+            out.write_all(&pixel[.. 3])?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
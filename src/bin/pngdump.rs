@@ -0,0 +1,22 @@
+use png::Decoder;
+use png::Error;
+use png::Result;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+
+// Prints the contents of every chunk of a PNG file (or standard input if the input is "-") without decoding the image,
+// including the palette histogram and suggested palettes, with warnings about the file on standard error
+fn main() -> Result<()> {
+    let mut args = env::args();
+    if args.len() != 2 {
+        return Err(Error::Format("Invalid number of arguments"));
+    }
+    let input = args.nth(1).unwrap();
+    let file: Box<dyn Read> = if input == "-" { Box::new(io::stdin()) } else { Box::new(File::open(&input)?) };
+    png::set_verbose(true);
+    Decoder::new(BufReader::new(file))?.read_metadata()?;
+    Ok(())
+}
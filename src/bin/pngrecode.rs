@@ -0,0 +1,82 @@
+use png::Decoder;
+use png::Encoder;
+use png::Error;
+use png::FilterStrategy;
+use png::FilterType;
+use png::Result;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+
+// Decodes a PNG file (or standard input if the input is "-") and encodes its samples again into a new PNG file,
+// keeping the color mode and interlace method. Only the image data, palette and transparency are written.
+// Usage: pngrecode [--level=N] [--filter=STRATEGY] [--report] INPUT OUTPUT
+// --level sets the compression level from 0 (none) to 9 (best), defaulting to 6.
+// --filter sets how scanlines are filtered: none, sub, up, average or paeth for the same filter type on every scanline,
+// minsum, entropy or brute to choose one per scanline.
+// --report prints how many scanlines use each filter type.
+fn main() -> Result<()> {
+    let mut compression_level = None;
+    let mut filter_strategy = None;
+    let mut report = false;
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        if let Some(level) = arg.strip_prefix("--level=") {
+            compression_level = Some(level.parse().map_err(|_| Error::Format("Invalid compression level"))?);
+        } else if let Some(strategy) = arg.strip_prefix("--filter=") {
+            filter_strategy = Some(match strategy {
+                "none" => FilterStrategy::Fixed(FilterType::None),
+                "sub" => FilterStrategy::Fixed(FilterType::Sub),
+                "up" => FilterStrategy::Fixed(FilterType::Up),
+                "average" => FilterStrategy::Fixed(FilterType::Average),
+                "paeth" => FilterStrategy::Fixed(FilterType::Paeth),
+                "minsum" => FilterStrategy::MinimumSum,
+                "entropy" => FilterStrategy::Entropy,
+                "brute" => FilterStrategy::BruteForce,
+                _ => return Err(Error::Format("Invalid filter strategy")),
+            });
+        } else if arg == "--report" {
+            report = true;
+        } else {
+            files.push(arg);
+        }
+    }
+    if files.len() != 2 {
+        return Err(Error::Format("Invalid number of arguments"));
+    }
+    let output = files.pop().unwrap();
+    let input = files.pop().unwrap();
+
+    let file: Box<dyn Read> = if input == "-" { Box::new(io::stdin()) } else { Box::new(File::open(&input)?) };
+    let decoder = Decoder::new(BufReader::new(file))?;
+    let info = decoder.info();
+    let pitch = info.color_mode.bytes_per_row(info.width);
+    let size = pitch.checked_mul(info.height as usize).ok_or(Error::Format("Image is too large"))?;
+    let mut samples = Vec::new();
+    samples.try_reserve_exact(size).map_err(|_| Error::Format("Image is too large"))?;
+    samples.resize(size, 0);
+    let info = decoder.decode_raw(&mut samples, pitch)?;
+
+    let mut encoder = Encoder::new(info.width, info.height, info.color_mode)?;
+    encoder.set_interlace_method(info.interlace_method);
+    encoder.set_transparency(info.transparency)?;
+    if let Some(compression_level) = compression_level {
+        encoder.set_compression_level(compression_level);
+    }
+    if let Some(filter_strategy) = filter_strategy {
+        encoder.set_filter_strategy(filter_strategy);
+    }
+    let mut out = encoder.encode(BufWriter::new(File::create(&output)?), &samples, pitch)?;
+    out.flush()?;
+    if report {
+        for filter_type in &[FilterType::None, FilterType::Sub, FilterType::Up, FilterType::Average, FilterType::Paeth] {
+            let count = encoder.filter_types().iter().filter(|&t| t == filter_type).count();
+            println!("{:?}: {}", filter_type, count);
+        }
+    }
+    Ok(())
+}
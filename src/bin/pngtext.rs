@@ -0,0 +1,29 @@
+use png::Decoder;
+use png::Error;
+use png::Result;
+use png::TextKind;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+
+// Prints the text entries of a PNG file (or standard input if the input is "-") without decoding the image,
+// one per line as the keyword followed by the text, with the language of international text in brackets
+fn main() -> Result<()> {
+    let mut args = env::args();
+    if args.len() != 2 {
+        return Err(Error::Format("Invalid number of arguments"));
+    }
+    let input = args.nth(1).unwrap();
+    let file: Box<dyn Read> = if input == "-" { Box::new(io::stdin()) } else { Box::new(File::open(&input)?) };
+    let info = Decoder::new(BufReader::new(file))?.read_metadata()?;
+    for entry in &info.text {
+        match &entry.kind {
+            TextKind::International { language, translated_keyword, .. } if !language.is_empty() || !translated_keyword.is_empty() =>
+                println!("{} [{}] ({}): {}", entry.keyword, language, translated_keyword, entry.text),
+            _ => println!("{}: {}", entry.keyword, entry.text),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use crate::chunk::ChunkReader;
+use crate::file::ByteReader;
+use crate::ihdr::ColorMode;
+use crate::Result;
+use std::io::Read;
+
+// Background color from a bKGD chunk.
+// Gray and RGB values are stored at the full bit depth of the image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Background {
+    Palette(u8),
+    Gray(u16),
+    RGB(u16, u16, u16),
+}
+
+impl Background {
+    // Converts the background to 8-bit RGB, returning None if it indexes past the end of the palette
+    #[allow(clippy::identity_op)]
+    pub fn to_rgb8(&self, color_mode: &ColorMode) -> Option<(u8, u8, u8)> {
+        use ColorMode::*;
+        let scale = |sample: u16| match color_mode {
+            Grayscale1 => (sample & 0x1) as u8 * (255 / 1),
+            Grayscale2 => (sample & 0x3) as u8 * (255 / 3),
+            Grayscale4 => (sample & 0xF) as u8 * (255 / 15),
+            Grayscale16 | GrayscaleAlpha16 | RGB16 | RGBA16 => (sample as f64 * 255.0 / 65535.0).round() as u8,
+            _ => sample as u8,
+        };
+        match *self {
+            Background::Palette(index) => color_mode.palette()?.get(index as usize).copied(),
+            Background::Gray(gray) => Some((scale(gray), scale(gray), scale(gray))),
+            Background::RGB(r, g, b) => Some((scale(r), scale(g), scale(b))),
+        }
+    }
+}
+
+// Returns None if the chunk is not valid for the color mode, in which case it should be ignored
+pub fn load_bkgd<R>(chunk: &mut ChunkReader<R>, length: u32, color_mode: &ColorMode) -> Result<Option<Background>> where R: Read {
+    use ColorMode::*;
+    let max_sample = match color_mode {
+        Grayscale1 => 0x1,
+        Grayscale2 => 0x3,
+        Grayscale4 => 0xF,
+        Grayscale8 | GrayscaleAlpha8 | RGB8 | RGBA8 => 0xFF,
+        _ => 0xFFFF,
+    };
+    match color_mode {
+        Palette1(palette) | Palette2(palette) | Palette4(palette) | Palette8(palette) => {
+            if length != 1 {
+                warn!("bKGD chunk has incorrect length for indexed color");
+                return Ok(None);
+            }
+            let index = chunk.read_u8()?;
+            info!("Background palette index: {}", index);
+            if index as usize >= palette.len() {
+                warn!("bKGD palette index past end of palette");
+                return Ok(None);
+            }
+            Ok(Some(Background::Palette(index)))
+        },
+        Grayscale1 | Grayscale2 | Grayscale4 | Grayscale8 | Grayscale16 | GrayscaleAlpha8 | GrayscaleAlpha16 => {
+            if length != 2 {
+                warn!("bKGD chunk has incorrect length for grayscale color");
+                return Ok(None);
+            }
+            let gray = chunk.read_u16()?;
+            info!("Background gray: {}", gray);
+            if gray > max_sample {
+                warn!("bKGD gray value exceeds bit depth");
+            }
+            Ok(Some(Background::Gray(gray)))
+        },
+        RGB8 | RGB16 | RGBA8 | RGBA16 => {
+            if length != 6 {
+                warn!("bKGD chunk has incorrect length for RGB color");
+                return Ok(None);
+            }
+            let red = chunk.read_u16()?;
+            let green = chunk.read_u16()?;
+            let blue = chunk.read_u16()?;
+            info!("Background color: ({}, {}, {})", red, green, blue);
+            if red > max_sample || green > max_sample || blue > max_sample {
+                warn!("bKGD color value exceeds bit depth");
+            }
+            Ok(Some(Background::RGB(red, green, blue)))
+        },
+    }
+}
@@ -1,46 +1,207 @@
+use crate::crc::Crc;
 use crate::file::ByteReader;
 use crate::Error;
 use crate::Result;
-use std::io::Seek;
-use std::fs::File;
+use std::io::Read;
 
-pub struct ChunkReader {
-    file: File,
+// What to do when the CRC of a chunk does not match its contents
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ChecksumPolicy {
+    Error,
+    Warn,
+    Ignore,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CrcPolicy {
+    pub critical: ChecksumPolicy,
+    pub ancillary: ChecksumPolicy,
+}
+
+impl Default for CrcPolicy {
+    fn default() -> Self {
+        CrcPolicy { critical: ChecksumPolicy::Error, ancillary: ChecksumPolicy::Warn }
+    }
+}
+
+pub struct ChunkReader<R> where R: Read {
+    file: R,
     length: u32,
     bytes_read: u32,
+    chunk_type: [u8; 4],
+    crc: Crc,
+    crc_policy: CrcPolicy,
 }
 
-impl ChunkReader {
-    pub fn new(mut file: File) -> Result<(ChunkReader, u32, Box<[u8]>)> {
-        println!("");
+impl<R> ChunkReader<R> where R: Read {
+    pub fn new(mut file: R, crc_policy: CrcPolicy) -> Result<(ChunkReader<R>, u32, [u8; 4])> {
+        info!("");
         let length = file.read_u32()?;
-        println!("Length: {}", length);
+        info!("Length: {}", length);
         if length > 0x7FFFFFFF {
             warn!("Length exceeds (2^31)-1");
         }
-        let chunk_type = file.read_buf(4)?;
-        println!("Chunk type: {:02X?} ({})", chunk_type, String::from_utf8_lossy(&chunk_type));
-        Ok((ChunkReader { file, length, bytes_read: 0 }, length, chunk_type))
+        let mut chunk_type = [0; 4];
+        file.read_exact(&mut chunk_type)?;
+        info!("Chunk type: {:02X?} ({})", chunk_type, String::from_utf8_lossy(&chunk_type));
+        let mut crc = Crc::new();
+        crc.update(&chunk_type);
+        Ok((ChunkReader { file, length, bytes_read: 0, chunk_type, crc, crc_policy }, length, chunk_type))
     }
 
-    pub fn end(mut self) -> Result<File> {
-        if self.bytes_read < self.length {
-            self.file.seek(std::io::SeekFrom::Current((self.length - self.bytes_read) as i64))?;
-            self.bytes_read = self.length;
+    pub fn crc_policy(&self) -> CrcPolicy {
+        self.crc_policy
+    }
+
+    // Reads bytes up to a null terminator, which is consumed but not returned
+    pub fn read_null_terminated(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        loop {
+            match self.read_u8()? {
+                0 => return Ok(bytes),
+                byte => bytes.push(byte),
+            }
+        }
+    }
+
+    // Reads the remaining contents of the chunk.
+    // The buffer grows as the data is read instead of being allocated for the declared length,
+    // so that a corrupt length cannot allocate more memory than the file has data for.
+    pub fn read_rest(&mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut buf = [0; 4096];
+        while self.bytes_read < self.length {
+            let len = u32::min(self.length - self.bytes_read, buf.len() as u32) as usize;
+            self.read_buf(&mut buf[.. len])?;
+            bytes.extend_from_slice(&buf[.. len]);
+        }
+        Ok(bytes)
+    }
+
+    // Reads a null-terminated Latin-1 keyword, as used by the iCCP, tEXt, zTXt, iTXt and sPLT chunks.
+    // Returns None if the keyword is not terminated within 80 bytes, in which case the chunk should be ignored.
+    pub fn read_keyword(&mut self) -> Result<Option<String>> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.read_u8()?;
+            if byte == 0 {
+                break;
+            }
+            if bytes.len() == 79 {
+                warn!("Keyword is longer than 79 bytes");
+                return Ok(None);
+            }
+            bytes.push(byte);
+        }
+        if bytes.is_empty() {
+            warn!("Keyword is empty");
+        }
+        if bytes.iter().any(|&b| !(32 ..= 126).contains(&b) && b < 161) {
+            warn!("Keyword contains non-printable characters");
+        }
+        if bytes.first() == Some(&b' ') || bytes.last() == Some(&b' ') || bytes.windows(2).any(|w| w == b"  ") {
+            warn!("Keyword contains leading, trailing or consecutive spaces");
+        }
+        Ok(Some(bytes.iter().map(|&b| b as char).collect()))
+    }
+
+    // Skips the rest of the chunk by reading it, so that the reader does not need to be seekable
+    pub fn end(mut self) -> Result<R> {
+        let policy = if self.chunk_type[0] & 0x20 == 0 { self.crc_policy.critical } else { self.crc_policy.ancillary };
+        let mut buf = [0; 4096];
+        while self.bytes_read < self.length {
+            let len = u32::min(self.length - self.bytes_read, buf.len() as u32) as usize;
+            self.read_buf(&mut buf[.. len])?;
+        }
+        let crc = self.file.read_u32()?;
+        info!("CRC: {:08X}", crc);
+        if policy != ChecksumPolicy::Ignore && crc != self.crc.value() {
+            let chunk_type = String::from_utf8_lossy(&self.chunk_type);
+            match policy {
+                ChecksumPolicy::Error => return Err(Error::Checksum(format!(
+                    "CRC-32 mismatch in {} chunk (expected {:08X}, got {:08X})", chunk_type, crc, self.crc.value()))),
+                _ => warn!("CRC-32 mismatch in {} chunk (expected {:08X}, got {:08X})", chunk_type, crc, self.crc.value()),
+            }
         }
-        self.file.read_u32()?;
         Ok(self.file)
     }
 }
 
-impl ByteReader for ChunkReader {
-    fn read_buf(&mut self, len: u32) -> Result<Box<[u8]>> {
-        if self.bytes_read + len > self.length {
-            let buf = self.file.read_buf(self.length - self.bytes_read)?;
-            return Err(Error::EndOfChunk(buf));
+impl<R> ByteReader for ChunkReader<R> where R: Read {
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<()> {
+        let left = (self.length - self.bytes_read) as usize;
+        if buf.len() > left {
+            self.file.read_exact(&mut buf[.. left])?;
+            self.crc.update(&buf[.. left]);
+            self.bytes_read = self.length;
+            return Err(Error::EndOfChunk(left));
         }
-        let buf = self.file.read_buf(len)?;
-        self.bytes_read += len;
-        Ok(buf)
+        self.file.read_exact(buf)?;
+        self.crc.update(buf);
+        self.bytes_read += buf.len() as u32;
+        Ok(())
+    }
+
+    fn read_available(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = usize::min(buf.len(), (self.length - self.bytes_read) as usize);
+        self.read_buf(&mut buf[.. len])?;
+        Ok(len)
+    }
+}
+
+// Frames chunk data with its length and CRC, for building files in tests
+#[cfg(test)]
+pub fn chunk_bytes(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    crate::encoder::write_chunk(&mut bytes, chunk_type, data).unwrap();
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A chunk with a corrupt CRC followed by a valid IEND chunk
+    fn corrupt_chunk(chunk_type: &[u8; 4]) -> Vec<u8> {
+        let mut bytes = chunk_bytes(chunk_type, b"data");
+        *bytes.last_mut().unwrap() ^= 0x01;
+        bytes.extend(chunk_bytes(b"IEND", &[]));
+        bytes
+    }
+
+    fn read_chunk(bytes: Vec<u8>, crc_policy: CrcPolicy) -> Result<Cursor<Vec<u8>>> {
+        let (mut chunk, length, _) = ChunkReader::new(Cursor::new(bytes), crc_policy)?;
+        let mut data = vec![0; length as usize];
+        chunk.read_buf(&mut data)?;
+        assert_eq!(data, b"data");
+        chunk.end()
+    }
+
+    #[test]
+    fn ancillary_crc_mismatch_warns_by_default() {
+        let file = read_chunk(corrupt_chunk(b"tEXt"), CrcPolicy::default()).unwrap();
+        let (chunk, length, chunk_type) = ChunkReader::new(file, CrcPolicy::default()).unwrap();
+        assert_eq!((length, chunk_type), (0, *b"IEND"));
+        assert!(chunk.end().is_ok());
+    }
+
+    #[test]
+    fn critical_crc_mismatch_is_an_error_by_default() {
+        assert!(matches!(read_chunk(corrupt_chunk(b"PLTE"), CrcPolicy::default()), Err(Error::Checksum(_))));
+    }
+
+    #[test]
+    fn crc_mismatch_is_ignored() {
+        let crc_policy = CrcPolicy { critical: ChecksumPolicy::Ignore, ancillary: ChecksumPolicy::Ignore };
+        assert!(read_chunk(corrupt_chunk(b"tEXt"), crc_policy).is_ok());
+        assert!(read_chunk(corrupt_chunk(b"PLTE"), crc_policy).is_ok());
+    }
+
+    #[test]
+    fn matching_crc() {
+        let crc_policy = CrcPolicy { critical: ChecksumPolicy::Error, ancillary: ChecksumPolicy::Error };
+        assert!(read_chunk(chunk_bytes(b"PLTE", b"data"), crc_policy).is_ok());
+        assert!(read_chunk(chunk_bytes(b"tEXt", b"data"), crc_policy).is_ok());
     }
 }
@@ -0,0 +1,290 @@
+use crate::chunk::ChunkReader;
+use crate::decoder::ImageInfo;
+use crate::file::ByteReader;
+use crate::file::SliceReader;
+use crate::icc;
+use crate::icc::IccColorSpace;
+use crate::ihdr::ColorMode;
+use crate::zlib;
+use crate::Error;
+use crate::Result;
+use std::io::Read;
+
+// Limit on the size of a decompressed ICC profile, so that a malicious file cannot exhaust memory
+const MAX_ICC_PROFILE_SIZE: usize = 1 << 26;
+
+pub type Matrix = [[f64; 3]; 3];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.0, 1.0],
+];
+
+const XYZ_TO_SRGB: Matrix = [
+    [ 3.2404542, -1.5371385, -0.4985314],
+    [-0.9692660,  1.8760108,  0.0415560],
+    [ 0.0556434, -0.2040259,  1.0572252],
+];
+
+const BRADFORD: Matrix = [
+    [ 0.8951,  0.2664, -0.1614],
+    [-0.7502,  1.7135,  0.0367],
+    [ 0.0389, -0.0685,  1.0296],
+];
+
+const WHITE_D65: [f64; 3] = [0.95047, 1.0, 1.08883];
+const WHITE_D50: [f64; 3] = [0.96422, 1.0, 0.82521];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RenderingIntent {
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    AbsoluteColorimetric,
+}
+
+// Chromaticities of the white point and primaries from a cHRM chunk, as (x, y) pairs times 100000
+#[derive(Copy, Clone, Debug)]
+pub struct Chromaticities {
+    pub white: (u32, u32),
+    pub red: (u32, u32),
+    pub green: (u32, u32),
+    pub blue: (u32, u32),
+}
+
+// Embedded ICC profile from an iCCP chunk, stored decompressed
+pub struct IccProfile {
+    pub name: String,
+    pub profile: Box<[u8]>,
+}
+
+// Returns None if the chunk is invalid, in which case it should be ignored
+pub fn load_srgb<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<RenderingIntent>> where R: Read {
+    if length != 1 {
+        warn!("sRGB chunk has incorrect length");
+        return Ok(None);
+    }
+    let intent = chunk.read_u8()?;
+    info!("Rendering intent: {}", intent);
+    Ok(match intent {
+        0 => Some(RenderingIntent::Perceptual),
+        1 => Some(RenderingIntent::RelativeColorimetric),
+        2 => Some(RenderingIntent::Saturation),
+        3 => Some(RenderingIntent::AbsoluteColorimetric),
+        _ => {
+            warn!("Invalid rendering intent");
+            None
+        },
+    })
+}
+
+pub fn load_chrm<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<Chromaticities>> where R: Read {
+    if length != 32 {
+        warn!("cHRM chunk has incorrect length");
+        return Ok(None);
+    }
+    let white = (chunk.read_u32()?, chunk.read_u32()?);
+    let red = (chunk.read_u32()?, chunk.read_u32()?);
+    let green = (chunk.read_u32()?, chunk.read_u32()?);
+    let blue = (chunk.read_u32()?, chunk.read_u32()?);
+    info!("White point: {:?}", white);
+    info!("Red: {:?}", red);
+    info!("Green: {:?}", green);
+    info!("Blue: {:?}", blue);
+    Ok(Some(Chromaticities { white, red, green, blue }))
+}
+
+// Returns None if the chunk is invalid or its profile cannot be decompressed, in which case it should be ignored
+pub fn load_iccp<R>(chunk: &mut ChunkReader<R>) -> Result<Option<IccProfile>> where R: Read {
+    let name = match chunk.read_keyword() {
+        Ok(Some(name)) => name,
+        Ok(None) => return Ok(None),
+        Err(Error::EndOfChunk(_)) => {
+            warn!("iCCP chunk ends within the profile name");
+            return Ok(None);
+        },
+        Err(err) => return Err(err),
+    };
+    info!("ICC profile name: {}", name);
+    let compression_method = match chunk.read_u8() {
+        Ok(compression_method) => compression_method,
+        Err(Error::EndOfChunk(_)) => {
+            warn!("iCCP chunk has no compression method");
+            return Ok(None);
+        },
+        Err(err) => return Err(err),
+    };
+    info!("Compression method: {}", compression_method);
+    if compression_method != 0 {
+        warn!("Unrecognized iCCP compression method");
+        return Ok(None);
+    }
+    let compressed = chunk.read_rest()?;
+    let mut profile = Vec::new();
+    if zlib::read_zlib(SliceReader::new(&compressed), &mut profile, MAX_ICC_PROFILE_SIZE, &[], |_| Ok(())).is_err() {
+        warn!("Invalid or oversized compressed ICC profile");
+        return Ok(None);
+    }
+    info!("ICC profile size: {}", profile.len());
+    Ok(Some(IccProfile { name, profile: profile.into_boxed_slice() }))
+}
+
+fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut c = [[0.0; 3]; 3];
+    for (i, row) in c.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0 .. 3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    c
+}
+
+fn apply(a: &Matrix, v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| a[i][0] * v[0] + a[i][1] * v[1] + a[i][2] * v[2])
+}
+
+fn invert(a: &Matrix) -> Option<Matrix> {
+    let det = a[0][0] * (a[1][1] * a[2][2] - a[1][2] * a[2][1])
+        - a[0][1] * (a[1][0] * a[2][2] - a[1][2] * a[2][0])
+        + a[0][2] * (a[1][0] * a[2][1] - a[1][1] * a[2][0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut inverse = [[0.0; 3]; 3];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+            let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+            *v = (a[j1][i1] * a[j2][i2] - a[j1][i2] * a[j2][i1]) / det;
+        }
+    }
+    Some(inverse)
+}
+
+// Bradford chromatic adaptation from one white point to another
+fn adaptation(from: [f64; 3], to: [f64; 3]) -> Matrix {
+    let cone_from = apply(&BRADFORD, from);
+    let cone_to = apply(&BRADFORD, to);
+    let mut scale = IDENTITY;
+    for i in 0 .. 3 {
+        scale[i][i] = cone_to[i] / cone_from[i];
+    }
+    multiply(&invert(&BRADFORD).unwrap(), &multiply(&scale, &BRADFORD))
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+// Matrix converting linear RGB with the given chromaticities to linear sRGB
+pub fn chromaticities_matrix(chromaticities: &Chromaticities) -> Option<Matrix> {
+    let xy = |(x, y): (u32, u32)| (x as f64 / 100000.0, y as f64 / 100000.0);
+    let (white, red, green, blue) = (xy(chromaticities.white), xy(chromaticities.red), xy(chromaticities.green), xy(chromaticities.blue));
+    if [white, red, green, blue].iter().any(|&(_, y)| y == 0.0) {
+        return None;
+    }
+    let white = xy_to_xyz(white);
+    let (red, green, blue) = (xy_to_xyz(red), xy_to_xyz(green), xy_to_xyz(blue));
+    let primaries = [
+        [red[0], green[0], blue[0]],
+        [red[1], green[1], blue[1]],
+        [red[2], green[2], blue[2]],
+    ];
+    let scale = apply(&invert(&primaries)?, white);
+    let mut rgb_to_xyz = primaries;
+    for row in rgb_to_xyz.iter_mut() {
+        for (v, s) in row.iter_mut().zip(scale) {
+            *v *= s;
+        }
+    }
+    Some(multiply(&XYZ_TO_SRGB, &multiply(&adaptation(white, WHITE_D65), &rgb_to_xyz)))
+}
+
+fn srgb_to_linear(x: f64) -> f64 {
+    if x <= 0.04045 { x / 12.92 } else { ((x + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(x: f64) -> f64 {
+    if x <= 0.0031308 { x * 12.92 } else { 1.055 * x.powf(1.0 / 2.4) - 0.055 }
+}
+
+// Conversion of RGB samples to 8-bit sRGB through linear light.
+// Each channel is first linearized by a lookup table, then the channels are mixed by a matrix
+// and clipped to the sRGB gamut, then encoded with the sRGB transfer function.
+pub struct ColorTransform {
+    linear_8: [Box<[f32]>; 3],
+    linear_16: Option<[Box<[f32]>; 3]>,
+    matrix: [[f32; 3]; 3],
+    encode: Box<[u8]>,
+}
+
+impl ColorTransform {
+    pub fn new<F>(to_linear: F, matrix: Matrix, sixteen_bit: bool) -> ColorTransform where F: Fn(usize, f64) -> f64 {
+        let table = |channel: usize, max: u32| (0 ..= max).map(|i| to_linear(channel, i as f64 / max as f64) as f32).collect();
+        let linear_8 = [0, 1, 2].map(|channel| table(channel, 0xFF));
+        let linear_16 = if sixteen_bit { Some([0, 1, 2].map(|channel| table(channel, 0xFFFF))) } else { None };
+        let matrix = matrix.map(|row| row.map(|v| v as f32));
+        let encode = (0 ..= 0xFFFF).map(|i| (linear_to_srgb(i as f64 / 65535.0) * 255.0).round() as u8).collect();
+        ColorTransform { linear_8, linear_16, matrix, encode }
+    }
+
+    fn convert_linear(&self, rgb: [f32; 3]) -> (u8, u8, u8) {
+        let [r, g, b] = [0, 1, 2].map(|i| {
+            let v = self.matrix[i][0] * rgb[0] + self.matrix[i][1] * rgb[1] + self.matrix[i][2] * rgb[2];
+            self.encode[(v.clamp(0.0, 1.0) * 65535.0).round() as usize]
+        });
+        (r, g, b)
+    }
+
+    pub fn convert_8(&self, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+        self.convert_linear([self.linear_8[0][r as usize], self.linear_8[1][g as usize], self.linear_8[2][b as usize]])
+    }
+
+    pub fn convert_16(&self, r: u16, g: u16, b: u16) -> (u8, u8, u8) {
+        match &self.linear_16 {
+            Some(linear_16) => self.convert_linear([linear_16[0][r as usize], linear_16[1][g as usize], linear_16[2][b as usize]]),
+            None => self.convert_8((r >> 8) as u8, (g >> 8) as u8, (b >> 8) as u8),
+        }
+    }
+}
+
+// Builds the transform converting the image to sRGB from the color space information in the file.
+// Following the PNG specification, iCCP takes precedence over sRGB, which takes precedence over cHRM and gAMA.
+// Returns None if the image is already in sRGB or has no color space information.
+pub fn srgb_transform(info: &ImageInfo) -> Option<ColorTransform> {
+    use ColorMode::*;
+    let gray = matches!(info.color_mode, Grayscale1 | Grayscale2 | Grayscale4 | Grayscale8 | Grayscale16 | GrayscaleAlpha8 | GrayscaleAlpha16);
+    let sixteen_bit = matches!(info.color_mode, Grayscale16 | GrayscaleAlpha16 | RGB16 | RGBA16);
+    if let Some(icc_profile) = &info.icc_profile {
+        match icc::parse_profile(&icc_profile.profile) {
+            Some(IccColorSpace::Rgb(matrix, curves)) if !gray => {
+                let matrix = multiply(&XYZ_TO_SRGB, &multiply(&adaptation(WHITE_D50, WHITE_D65), &matrix));
+                return Some(ColorTransform::new(|channel, x| curves[channel].eval(x), matrix, sixteen_bit));
+            },
+            Some(IccColorSpace::Gray(curve)) if gray => {
+                return Some(ColorTransform::new(|_, x| curve.eval(x), IDENTITY, sixteen_bit));
+            },
+            Some(_) => warn!("ICC profile color space does not match the color type"),
+            None => (),
+        }
+    }
+    if info.srgb.is_some() {
+        return None;
+    }
+    let gamma = info.gamma;
+    let to_linear = |_, x: f64| match gamma {
+        Some(gamma) => x.powf(100000.0 / gamma as f64),
+        None => srgb_to_linear(x),
+    };
+    if let Some(chromaticities) = &info.chromaticities {
+        match chromaticities_matrix(chromaticities) {
+            Some(matrix) => return Some(ColorTransform::new(to_linear, matrix, sixteen_bit)),
+            None => warn!("Invalid chromaticities"),
+        }
+    }
+    if gamma.is_some() {
+        return Some(ColorTransform::new(to_linear, IDENTITY, sixteen_bit));
+    }
+    None
+}
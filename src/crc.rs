@@ -0,0 +1,38 @@
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+// CRC-32 as used by PNG chunks, computed incrementally over the bytes passed to update
+pub struct Crc {
+    crc: u32,
+}
+
+impl Crc {
+    pub fn new() -> Crc {
+        Crc { crc: 0xFFFFFFFF }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            self.crc = CRC_TABLE[((self.crc ^ byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.crc ^ 0xFFFFFFFF
+    }
+}
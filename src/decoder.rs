@@ -0,0 +1,600 @@
+use crate::apng;
+use crate::apng::AnimationControl;
+use crate::apng::FrameControl;
+use crate::apng::Frames;
+use crate::bkgd;
+use crate::bkgd::Background;
+use crate::chunk::ChunkReader;
+use crate::chunk::CrcPolicy;
+use crate::color;
+use crate::color::Chromaticities;
+use crate::color::ColorTransform;
+use crate::color::IccProfile;
+use crate::color::RenderingIntent;
+use crate::file::ByteReader;
+use crate::exif;
+use crate::exif::Exif;
+use crate::filter;
+use crate::filter::Conversion;
+use crate::gamma;
+use crate::gamma::GammaTables;
+use crate::hdr;
+use crate::hdr::CodingIndependentCodePoints;
+use crate::hdr::ContentLightLevel;
+use crate::hdr::MasteringDisplay;
+use crate::idat::IdatReader;
+use crate::ihdr;
+use crate::ihdr::ColorMode;
+use crate::ihdr::Histogram;
+use crate::ihdr::InterlaceMethod;
+use crate::ihdr::PartialColorMode;
+use crate::ihdr::SuggestedPalette;
+use crate::phys;
+use crate::phys::PhysicalDimensions;
+use crate::sbit;
+use crate::sbit::SignificantBits;
+use crate::text;
+use crate::text::TextEntry;
+use crate::time;
+use crate::time::Timestamp;
+use crate::trns;
+use crate::trns::Transparency;
+use crate::zlib;
+use crate::Error;
+use crate::Result;
+use std::io::Read;
+
+pub const PNG_SIG : [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub color_mode: ColorMode,
+    pub interlace_method: InterlaceMethod,
+    pub transparency: Option<Transparency>,
+    // Gamma times 100000
+    pub gamma: Option<u32>,
+    pub srgb: Option<RenderingIntent>,
+    pub chromaticities: Option<Chromaticities>,
+    pub icc_profile: Option<IccProfile>,
+    // Text entries in file order, including those after the image data once it has been read
+    pub text: Vec<TextEntry>,
+    pub physical_dimensions: Option<PhysicalDimensions>,
+    pub modification_time: Option<Timestamp>,
+    pub background: Option<Background>,
+    pub significant_bits: Option<SignificantBits>,
+    pub histogram: Option<Histogram>,
+    pub suggested_palettes: Vec<SuggestedPalette>,
+    pub exif: Option<Exif>,
+    pub cicp: Option<CodingIndependentCodePoints>,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light_level: Option<ContentLightLevel>,
+    pub animation: Option<AnimationControl>,
+    // Frame control of the default image if it is the first frame of the animation
+    pub default_image_frame: Option<FrameControl>,
+}
+
+// Options controlling how samples are converted to output pixels
+#[derive(Copy, Clone, Default)]
+struct OutputSettings {
+    display_gamma: Option<f64>,
+    srgb_output: bool,
+    rescale_significant_bits: bool,
+    tone_mapping: bool,
+}
+
+// Lookup tables and transforms built from the output settings, shared by every frame of the image
+pub struct ConversionTables {
+    gamma_tables: Option<GammaTables>,
+    color_transform: Option<ColorTransform>,
+    rescale_significant_bits: bool,
+}
+
+impl ConversionTables {
+    fn new(info: &ImageInfo, settings: OutputSettings) -> ConversionTables {
+        let mut color_transform = if settings.tone_mapping { hdr::tone_mapping_transform(info) } else { None };
+        if color_transform.is_none() && settings.srgb_output {
+            color_transform = color::srgb_transform(info);
+        }
+        let gamma_tables = match (info.gamma, settings.display_gamma, &color_transform) {
+            (Some(gamma), Some(display_gamma), None) => Some(GammaTables::new(gamma, display_gamma)),
+            _ => None,
+        };
+        ConversionTables { gamma_tables, color_transform, rescale_significant_bits: settings.rescale_significant_bits }
+    }
+
+    pub fn conversion<'a>(&'a self, info: &'a ImageInfo) -> Conversion<'a> {
+        Conversion {
+            transparency: info.transparency.as_ref(),
+            gamma: self.gamma_tables.as_ref(),
+            color_transform: self.color_transform.as_ref(),
+            significant_bits: if self.rescale_significant_bits { info.significant_bits.as_ref() } else { None },
+        }
+    }
+}
+
+// Decodes a PNG file from any reader into a buffer of 8-bit RGBA pixels.
+// Creating the decoder reads all chunks up to the image data, so that the image information is available before decoding.
+// The reader is read in small pieces, so it should be buffered.
+pub struct Decoder<R> where R: Read {
+    info: ImageInfo,
+    idat: ChunkReader<R>,
+    settings: OutputSettings,
+}
+
+impl<R> Decoder<R> where R: Read {
+    pub fn new(file: R) -> Result<Decoder<R>> {
+        Decoder::with_crc_policy(file, CrcPolicy::default())
+    }
+
+    // Creates a decoder which handles chunks with incorrect CRCs according to the policy
+    pub fn with_crc_policy(mut file: R, crc_policy: CrcPolicy) -> Result<Decoder<R>> {
+        let mut sig = [0; 8];
+        file.read_exact(&mut sig)?;
+        info!("Signature: {:02X?}", sig);
+        if sig != PNG_SIG {
+            return Err(Error::Format("Invalid PNG signature"));
+        }
+        let (mut file, width, height, mut partial_color_mode, interlace_method) = ihdr::load_ihdr(file, crc_policy)?;
+        let mut after_plte = false;
+        let mut transparency = None;
+        let mut gamma = None;
+        let mut srgb = None;
+        let mut chromaticities = None;
+        let mut icc_profile = None;
+        let mut text = Vec::new();
+        let mut physical_dimensions = None;
+        let mut modification_time = None;
+        let mut background = None;
+        let mut significant_bits = None;
+        let mut palette_len = None;
+        let mut histogram = None;
+        let mut suggested_palettes: Vec<SuggestedPalette> = Vec::new();
+        let mut exif = None;
+        let mut cicp = None;
+        let mut mastering_display = None;
+        let mut content_light_level = None;
+        let mut animation = None;
+        let mut default_image_frame = None;
+        loop {
+            let (mut chunk, length, chunk_type) = ChunkReader::new(file, crc_policy)?;
+            match &chunk_type {
+                b"IHDR" => {
+                    warn!("Multiple IHDR chunks");
+                },
+                b"PLTE" => {
+                    if after_plte {
+                        warn!("Multiple PLTE chunks");
+                    }
+                    after_plte = true;
+                    let palette = ihdr::load_palette(&mut chunk, length)?;
+                    palette_len = Some(palette.len());
+                    match partial_color_mode {
+                        PartialColorMode::Full(ColorMode::Grayscale1) |
+                        PartialColorMode::Full(ColorMode::Grayscale2) |
+                        PartialColorMode::Full(ColorMode::Grayscale4) |
+                        PartialColorMode::Full(ColorMode::Grayscale8) |
+                        PartialColorMode::Full(ColorMode::Grayscale16) |
+                        PartialColorMode::Full(ColorMode::GrayscaleAlpha8) |
+                        PartialColorMode::Full(ColorMode::GrayscaleAlpha16) =>
+                            warn!("PLTE chunk with grayscale color"),
+                        PartialColorMode::Partial(f) => partial_color_mode = PartialColorMode::Full(f(palette)),
+                        _ => (),
+                    }
+                    let max_palette = match partial_color_mode {
+                        PartialColorMode::Full(ColorMode::Palette1(_)) => 2,
+                        PartialColorMode::Full(ColorMode::Palette2(_)) => 4,
+                        PartialColorMode::Full(ColorMode::Palette4(_)) => 16,
+                        _ => 256,
+                    };
+                    if length / 3 > max_palette {
+                        warn!("Palette exceeds maximum length for color type");
+                    }
+                },
+                b"gAMA" => {
+                    if gamma.is_some() {
+                        warn!("Multiple gAMA chunks");
+                    }
+                    if after_plte {
+                        warn!("gAMA chunk after PLTE chunk");
+                    }
+                    gamma = gamma::load_gama(&mut chunk, length)?;
+                },
+                b"sBIT" => {
+                    if significant_bits.is_some() {
+                        warn!("Multiple sBIT chunks");
+                    }
+                    if after_plte {
+                        warn!("sBIT chunk after PLTE chunk");
+                    }
+                    significant_bits = sbit::load_sbit(&mut chunk, length, &partial_color_mode)?;
+                },
+                b"cICP" => {
+                    if cicp.is_some() {
+                        warn!("Multiple cICP chunks");
+                    }
+                    if after_plte {
+                        warn!("cICP chunk after PLTE chunk");
+                    }
+                    cicp = hdr::load_cicp(&mut chunk, length)?;
+                },
+                b"mDCv" => {
+                    if mastering_display.is_some() {
+                        warn!("Multiple mDCv chunks");
+                    }
+                    if after_plte {
+                        warn!("mDCv chunk after PLTE chunk");
+                    }
+                    mastering_display = hdr::load_mdcv(&mut chunk, length)?;
+                },
+                b"cLLi" => {
+                    if content_light_level.is_some() {
+                        warn!("Multiple cLLi chunks");
+                    }
+                    if after_plte {
+                        warn!("cLLi chunk after PLTE chunk");
+                    }
+                    content_light_level = hdr::load_clli(&mut chunk, length)?;
+                },
+                b"sRGB" => {
+                    if srgb.is_some() {
+                        warn!("Multiple sRGB chunks");
+                    }
+                    if after_plte {
+                        warn!("sRGB chunk after PLTE chunk");
+                    }
+                    if icc_profile.is_some() {
+                        warn!("sRGB chunk together with iCCP chunk");
+                    }
+                    srgb = color::load_srgb(&mut chunk, length)?;
+                },
+                b"cHRM" => {
+                    if chromaticities.is_some() {
+                        warn!("Multiple cHRM chunks");
+                    }
+                    if after_plte {
+                        warn!("cHRM chunk after PLTE chunk");
+                    }
+                    chromaticities = color::load_chrm(&mut chunk, length)?;
+                },
+                b"iCCP" => {
+                    if icc_profile.is_some() {
+                        warn!("Multiple iCCP chunks");
+                    }
+                    if after_plte {
+                        warn!("iCCP chunk after PLTE chunk");
+                    }
+                    if srgb.is_some() {
+                        warn!("iCCP chunk together with sRGB chunk");
+                    }
+                    icc_profile = color::load_iccp(&mut chunk)?;
+                },
+                b"hIST" => {
+                    if histogram.is_some() {
+                        warn!("Multiple hIST chunks");
+                    }
+                    match palette_len {
+                        Some(palette_len) => histogram = ihdr::load_hist(&mut chunk, length, palette_len)?,
+                        None => warn!("hIST chunk before PLTE chunk"),
+                    }
+                },
+                b"sPLT" => {
+                    if let Some(palette) = ihdr::load_splt(&mut chunk, length)? {
+                        if suggested_palettes.iter().any(|other| other.name == palette.name) {
+                            warn!("Multiple sPLT chunks with the same name");
+                        }
+                        suggested_palettes.push(palette);
+                    }
+                },
+                b"tRNS" => {
+                    if transparency.is_some() {
+                        warn!("Multiple tRNS chunks");
+                    }
+                    match partial_color_mode {
+                        PartialColorMode::Full(ref color_mode) => transparency = trns::load_trns(&mut chunk, length, color_mode)?,
+                        PartialColorMode::Partial(_) => warn!("tRNS chunk before PLTE chunk"),
+                    }
+                },
+                b"bKGD" => {
+                    if background.is_some() {
+                        warn!("Multiple bKGD chunks");
+                    }
+                    match partial_color_mode {
+                        PartialColorMode::Full(ref color_mode) => background = bkgd::load_bkgd(&mut chunk, length, color_mode)?,
+                        PartialColorMode::Partial(_) => warn!("bKGD chunk before PLTE chunk"),
+                    }
+                },
+                b"pHYs" => {
+                    if physical_dimensions.is_some() {
+                        warn!("Multiple pHYs chunks");
+                    }
+                    physical_dimensions = phys::load_phys(&mut chunk, length)?;
+                },
+                b"tIME" => {
+                    if modification_time.is_some() {
+                        warn!("Multiple tIME chunks");
+                    }
+                    modification_time = time::load_time(&mut chunk, length)?;
+                },
+                b"eXIf" => {
+                    if exif.is_some() {
+                        warn!("Multiple eXIf chunks");
+                    }
+                    exif = exif::load_exif(&mut chunk)?;
+                },
+                b"acTL" => {
+                    if animation.is_some() {
+                        warn!("Multiple acTL chunks");
+                    }
+                    animation = apng::load_actl(&mut chunk, length)?;
+                },
+                b"fcTL" => {
+                    if default_image_frame.is_some() {
+                        warn!("Multiple fcTL chunks before IDAT chunk");
+                    }
+                    default_image_frame = apng::load_fctl(&mut chunk, length)?;
+                },
+                b"fdAT" => {
+                    warn!("fdAT chunk before IDAT chunk");
+                },
+                b"tEXt" => {
+                    text.extend(text::load_text(&mut chunk, length)?);
+                },
+                b"zTXt" => {
+                    text.extend(text::load_ztxt(&mut chunk, length)?);
+                },
+                b"iTXt" => {
+                    text.extend(text::load_itxt(&mut chunk, length)?);
+                },
+                b"IDAT" => {
+                    let color_mode = match partial_color_mode {
+                        PartialColorMode::Full(mode) => mode,
+                        PartialColorMode::Partial(_) => return Err(Error::Format("No PLTE chunk befor IDAT with indexed colors")),
+                    };
+                    let info = ImageInfo {
+                        width, height, color_mode, interlace_method, transparency, gamma, srgb, chromaticities, icc_profile, text,
+                        physical_dimensions, modification_time, background, significant_bits, histogram, suggested_palettes,
+                        exif, cicp, mastering_display, content_light_level, animation, default_image_frame,
+                    };
+                    return Ok(Decoder { info, idat: chunk, settings: OutputSettings::default() });
+                },
+                b"IEND" => {
+                    return Err(Error::Format("No IDAT chunk before IEND chunk"));
+                },
+                _ => {
+                    read_unknown_chunk(&chunk_type);
+                },
+            }
+            file = chunk.end()?;
+        }
+    }
+
+    pub fn info(&self) -> &ImageInfo {
+        &self.info
+    }
+
+    // Enables gamma correction from the gamma of the file, if it specifies one, to the given display gamma
+    pub fn set_display_gamma(&mut self, display_gamma: f64) {
+        self.settings.display_gamma = Some(display_gamma);
+    }
+
+    // Enables conversion of the image to sRGB using the color space information in the file.
+    // When the file has any, it is used instead of gamma correction to the display gamma.
+    pub fn set_srgb_output(&mut self, srgb_output: bool) {
+        self.settings.srgb_output = srgb_output;
+    }
+
+    // Enables mapping samples with fewer significant bits than the bit depth, as given by the sBIT chunk,
+    // to the full output range, instead of treating the insignificant bits as part of the sample
+    pub fn set_rescale_significant_bits(&mut self, rescale_significant_bits: bool) {
+        self.settings.rescale_significant_bits = rescale_significant_bits;
+    }
+
+    // Enables tone mapping images with a PQ or HLG transfer function given by the cICP chunk to SDR sRGB,
+    // which takes precedence over conversion to sRGB and gamma correction
+    pub fn set_tone_mapping(&mut self, tone_mapping: bool) {
+        self.settings.tone_mapping = tone_mapping;
+    }
+
+    // Decodes the image into the pixel buffer, in which each row starts pitch bytes after the previous one.
+    // Returns the image information updated with the metadata from chunks after the image data.
+    pub fn decode(self, pixels: &mut [u8], pitch: usize) -> Result<ImageInfo> {
+        self.decode_progressive(pixels, pitch, |_| Ok(()))
+    }
+
+    // Decodes the image like decode, calling the callback with the pixel buffer after each pass is decoded.
+    // Pixels of Adam7 passes are replicated over the parts of the image which have not been decoded yet.
+    pub fn decode_progressive<F>(self, pixels: &mut [u8], pitch: usize, on_pass: F) -> Result<ImageInfo> where F: FnMut(&[u8]) -> Result<()> {
+        let Decoder { mut info, idat, settings } = self;
+        if pitch < info.width as usize * 4 || pixels.len() < pitch * (info.height as usize - 1) + info.width as usize * 4 {
+            return Err(Error::Format("Pixel buffer is too small for the image"));
+        }
+        let tables = ConversionTables::new(&info, settings);
+        let conversion = tables.conversion(&info);
+        let chunk = decode_image_data(IdatReader::new(idat)?, pixels, pitch, info.width, info.height, &info, &conversion, on_pass)?.end()?;
+        let crc_policy = chunk.crc_policy();
+        read_trailing_chunks(chunk.end()?, crc_policy, &mut info, false)?;
+        Ok(info)
+    }
+
+    // Decodes the image without converting its samples, into rows of packed samples laid out as in unfiltered scanlines
+    // without the filter type byte, each starting pitch bytes after the previous one.
+    // The palette, transparency and other metadata needed to interpret the samples are left in the image information.
+    pub fn decode_raw(self, samples: &mut [u8], pitch: usize) -> Result<ImageInfo> {
+        let Decoder { mut info, idat, .. } = self;
+        let bytes_per_row = info.color_mode.bytes_per_row(info.width);
+        if pitch < bytes_per_row || samples.len() < pitch * (info.height as usize - 1) + bytes_per_row {
+            return Err(Error::Format("Sample buffer is too small for the image"));
+        }
+        let ImageInfo { width, height, ref color_mode, interlace_method, .. } = info;
+        let chunk = read_image_data(IdatReader::new(idat)?, width, height, color_mode, interlace_method, |data, pass| {
+            filter::unfilter_uninterlace_pass_raw(data, samples, pitch, width, height, color_mode, interlace_method, pass)
+        })?.end()?;
+        let crc_policy = chunk.crc_policy();
+        read_trailing_chunks(chunk.end()?, crc_policy, &mut info, false)?;
+        Ok(info)
+    }
+
+    // Returns an iterator over the frames of an animated image, each composited onto the full image.
+    // Images without an acTL chunk produce a single frame with the default image.
+    pub fn frames(self) -> Result<Frames<R>> {
+        let Decoder { info, idat, settings } = self;
+        let tables = ConversionTables::new(&info, settings);
+        Frames::new(info, tables, idat)
+    }
+
+    // Reads the rest of the file without decoding the image data,
+    // returning the image information updated with the metadata from chunks after the image data
+    pub fn read_metadata(self) -> Result<ImageInfo> {
+        let Decoder { mut info, idat, .. } = self;
+        let crc_policy = idat.crc_policy();
+        read_trailing_chunks(idat.end()?, crc_policy, &mut info, true)?;
+        Ok(info)
+    }
+}
+
+// Decompresses image data of the given size from the reader, unfiltering and converting it into the pixel buffer.
+// The callback is called with the pixel buffer after each pass.
+#[allow(clippy::too_many_arguments)]
+pub fn decode_image_data<T, F>(reader: T, pixels: &mut [u8], pitch: usize, width: u32, height: u32, info: &ImageInfo, conversion: &Conversion, mut on_pass: F) -> Result<T> where T: ByteReader, F: FnMut(&[u8]) -> Result<()> {
+    let ImageInfo { ref color_mode, interlace_method, .. } = *info;
+    read_image_data(reader, width, height, color_mode, interlace_method, |data, pass| {
+        filter::unfilter_uninterlace_pass(data, pixels, pitch, width, height, color_mode, conversion, interlace_method, pass)?;
+        on_pass(pixels)
+    })
+}
+
+// Decompresses image data of the given size from the reader,
+// calling the callback with the data decompressed so far and the index of the pass once each pass is complete
+fn read_image_data<T, F>(reader: T, width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod, mut on_pass: F) -> Result<T> where T: ByteReader, F: FnMut(&[u8], usize) -> Result<()> {
+    let pass_ends = filter::pass_ends(width, height, color_mode, interlace_method);
+    let len = pass_ends[pass_ends.len() - 1];
+    let mut buf = Vec::with_capacity(len);
+    let mut pass = 0;
+    let reader = zlib::read_zlib(reader, &mut buf, len, &pass_ends, |data| {
+        on_pass(data, pass)?;
+        pass += 1;
+        Ok(())
+    })?;
+    if buf.len() < len {
+        return Err(Error::Format("Not enough image data"));
+    }
+    Ok(reader)
+}
+
+// Reads the chunks after the image data up to IEND, adding their metadata to the image information.
+// If skipping image data, the IDAT chunks directly following the first one are expected.
+fn read_trailing_chunks<R>(mut file: R, crc_policy: CrcPolicy, info: &mut ImageInfo, skip_idat: bool) -> Result<()> where R: Read {
+    let mut in_idat = skip_idat;
+    loop {
+        let (chunk, length, chunk_type) = ChunkReader::new(file, crc_policy)?;
+        if chunk_type != *b"IDAT" {
+            in_idat = false;
+        }
+        file = read_trailing_chunk(chunk, length, &chunk_type, info, in_idat)?.end()?;
+        if chunk_type == *b"IEND" {
+            // TODO check for EOF
+            return Ok(());
+        }
+    }
+}
+
+// Reads a single chunk after the image data, returning the reader of the chunk so that it can be ended.
+// IDAT chunks are expected only if directly following the image data.
+pub fn read_trailing_chunk<R>(mut chunk: ChunkReader<R>, length: u32, chunk_type: &[u8; 4], info: &mut ImageInfo, in_idat: bool) -> Result<ChunkReader<R>> where R: Read {
+    match chunk_type {
+        b"IHDR" => {
+            warn!("Multiple IHDR chunks");
+        },
+        b"PLTE" => {
+            warn!("PLTE chunk after IDAT chunk");
+        },
+        b"gAMA" => {
+            warn!("gAMA chunk after IDAT chunk");
+        },
+        b"tRNS" => {
+            warn!("tRNS chunk after IDAT chunk");
+        },
+        b"hIST" => {
+            warn!("hIST chunk after IDAT chunk");
+        },
+        b"sPLT" => {
+            warn!("sPLT chunk after IDAT chunk");
+        },
+        b"sBIT" => {
+            warn!("sBIT chunk after IDAT chunk");
+        },
+        b"cICP" => {
+            warn!("cICP chunk after IDAT chunk");
+        },
+        b"mDCv" => {
+            warn!("mDCv chunk after IDAT chunk");
+        },
+        b"cLLi" => {
+            warn!("cLLi chunk after IDAT chunk");
+        },
+        b"sRGB" => {
+            warn!("sRGB chunk after IDAT chunk");
+        },
+        b"cHRM" => {
+            warn!("cHRM chunk after IDAT chunk");
+        },
+        b"iCCP" => {
+            warn!("iCCP chunk after IDAT chunk");
+        },
+        b"bKGD" => {
+            warn!("bKGD chunk after IDAT chunk");
+        },
+        b"pHYs" => {
+            warn!("pHYs chunk after IDAT chunk");
+        },
+        b"acTL" => {
+            warn!("acTL chunk after IDAT chunk");
+        },
+        b"fcTL" | b"fdAT" => {
+            // Animation frames are only decoded by the frame iterator
+        },
+        b"tIME" => {
+            if info.modification_time.is_some() {
+                warn!("Multiple tIME chunks");
+            }
+            info.modification_time = time::load_time(&mut chunk, length)?;
+        },
+        b"eXIf" => {
+            if info.exif.is_some() {
+                warn!("Multiple eXIf chunks");
+            }
+            info.exif = exif::load_exif(&mut chunk)?;
+        },
+        b"tEXt" => {
+            info.text.extend(text::load_text(&mut chunk, length)?);
+        },
+        b"zTXt" => {
+            info.text.extend(text::load_ztxt(&mut chunk, length)?);
+        },
+        b"iTXt" => {
+            info.text.extend(text::load_itxt(&mut chunk, length)?);
+        },
+        b"IDAT" => {
+            if !in_idat {
+                warn!("More IDAT chunks");
+            }
+        },
+        b"IEND" => {
+            if length != 0 {
+                warn!("IEND chunk has nonzero length");
+            }
+        },
+        _ => {
+            read_unknown_chunk(chunk_type);
+        },
+    }
+    Ok(chunk)
+}
+
+fn read_unknown_chunk(chunk_type: &[u8]) {
+    // TODO warn on invalid chunk types
+    if chunk_type[0] & 0x20 == 0 {
+        warn!("Unrecognized critical chunk");
+    }
+}
@@ -0,0 +1,637 @@
+use crate::zlib::adler32;
+use crate::zlib::CODE_LENGTH_ORDER;
+use crate::zlib::DISTANCE_CODE_INTERPRETATION;
+use crate::zlib::LENGTH_CODE_INTERPRETATION;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+const WINDOW_SIZE: usize = 1 << 15;
+const HASH_BITS: u32 = 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// Matches of the minimum length are only worth it if their distance is short enough
+const TOO_FAR: usize = 4096;
+// Number of literals and matches collected before they are written out as a block
+const BLOCK_SYMBOLS: usize = 1 << 15;
+// Largest number of bytes in a stored block
+const MAX_STORED_LEN: usize = 65535;
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+const END_OF_BLOCK: usize = 256;
+
+pub const DEFAULT_COMPRESSION_LEVEL: u8 = 6;
+pub const MAX_COMPRESSION_LEVEL: u8 = 9;
+
+// Match finding parameters of a compression level, as used by zlib:
+// a match at least good_length long reduces the search for a longer one at the next position,
+// a match at least max_lazy long is taken without looking at the next position,
+// a match at least nice_length long ends the search,
+// and at most max_chain earlier positions with the same hash are compared.
+struct LevelConfig {
+    good_length: usize,
+    max_lazy: usize,
+    nice_length: usize,
+    max_chain: usize,
+    lazy: bool,
+}
+
+const fn config(good_length: usize, max_lazy: usize, nice_length: usize, max_chain: usize, lazy: bool) -> LevelConfig {
+    LevelConfig { good_length, max_lazy, nice_length, max_chain, lazy }
+}
+
+// Configurations for levels 1 to 9. Level 0 only writes stored blocks.
+const LEVEL_CONFIGS: [LevelConfig; 9] = [
+    config(4, 4, 8, 4, false),
+    config(4, 5, 16, 8, false),
+    config(4, 6, 32, 32, false),
+    config(4, 4, 16, 16, true),
+    config(8, 16, 32, 32, true),
+    config(8, 16, 128, 128, true),
+    config(8, 32, 128, 256, true),
+    config(32, 128, 258, 1024, true),
+    config(32, 258, 258, 4096, true),
+];
+
+// Writes bits starting from the least significant bit of each byte
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    bits_used: u8,
+}
+
+impl BitWriter {
+    fn new(out: Vec<u8>) -> BitWriter {
+        BitWriter { out, bits: 0, bits_used: 0 }
+    }
+
+    fn write_bits(&mut self, bits: u32, len: u8) {
+        self.bits |= (bits as u64) << self.bits_used;
+        self.bits_used += len;
+        while self.bits_used >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bits_used -= 8;
+        }
+    }
+
+    // Pads the partially written byte with zero bits
+    fn align(&mut self) {
+        if self.bits_used > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.bits_used = 0;
+        }
+    }
+
+    fn end(mut self) -> Vec<u8> {
+        self.align();
+        self.out
+    }
+}
+
+// Computes code lengths of at most max_len bits for symbols with the given frequencies.
+// At least two symbols must have nonzero frequencies, so that the code is complete.
+fn code_lengths(freqs: &[u32], max_len: u8) -> Vec<u8> {
+    let mut lengths = vec![0; freqs.len()];
+    // Build the Huffman tree, in which leaves are symbols and each inner node stores its children
+    let mut children = Vec::new();
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = freqs.iter().enumerate()
+        .filter(|&(_, &freq)| freq > 0)
+        .map(|(symbol, &freq)| Reverse((freq as u64, symbol)))
+        .collect();
+    let symbols = heap.len();
+    while heap.len() > 1 {
+        let Reverse((freq_a, a)) = heap.pop().unwrap();
+        let Reverse((freq_b, b)) = heap.pop().unwrap();
+        children.push((a, b));
+        heap.push(Reverse((freq_a + freq_b, freqs.len() + children.len() - 1)));
+    }
+    // Count the leaves at each depth, clamping depths beyond the maximum
+    let mut count = vec![0usize; max_len as usize + 1];
+    let mut stack = vec![(freqs.len() + children.len() - 1, 0)];
+    while let Some((node, depth)) = stack.pop() {
+        if node < freqs.len() {
+            count[usize::min(depth, max_len as usize)] += 1;
+        } else {
+            let (a, b) = children[node - freqs.len()];
+            stack.push((a, depth + 1));
+            stack.push((b, depth + 1));
+        }
+    }
+    // If depths were clamped, the code is over-subscribed.
+    // Lengthen codes one at a time, each turning a code into two codes one bit longer, until it is complete.
+    let mut total: usize = (1 ..= max_len as usize).map(|len| count[len] << (max_len as usize - len)).sum();
+    while total > 1 << max_len {
+        count[max_len as usize] -= 1;
+        for len in (1 .. max_len as usize).rev() {
+            if count[len] > 0 {
+                count[len] -= 1;
+                count[len + 1] += 2;
+                break;
+            }
+        }
+        total -= 1;
+    }
+    // Give the shortest codes to the most frequent symbols
+    let mut sorted: Vec<usize> = (0 .. freqs.len()).filter(|&symbol| freqs[symbol] > 0).collect();
+    sorted.sort_by_key(|&symbol| Reverse(freqs[symbol]));
+    let mut len = 1;
+    for &symbol in &sorted[.. symbols] {
+        while count[len] == 0 {
+            len += 1;
+        }
+        count[len] -= 1;
+        lengths[symbol] = len as u8;
+    }
+    lengths
+}
+
+// Assigns canonical codes to the code lengths, bit-reversed so that they can be written starting from the least significant bit
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut count = [0u16; MAX_CODE_LENGTH as usize + 1];
+    for &len in lengths {
+        count[len as usize] += 1;
+    }
+    count[0] = 0;
+    let mut next_code = [0u16; MAX_CODE_LENGTH as usize + 2];
+    for len in 1 ..= MAX_CODE_LENGTH as usize {
+        next_code[len + 1] = (next_code[len] + count[len]) << 1;
+    }
+    lengths.iter().map(|&len| {
+        if len == 0 {
+            return 0;
+        }
+        let code = next_code[len as usize];
+        next_code[len as usize] += 1;
+        code.reverse_bits() >> (16 - len as u32)
+    }).collect()
+}
+
+// Makes sure at least two symbols have nonzero frequencies, as the decoder only accepts complete codes
+fn ensure_two_symbols(freqs: &mut [u32]) {
+    let mut used = freqs.iter().filter(|&&freq| freq > 0).count();
+    for freq in freqs.iter_mut() {
+        if used >= 2 {
+            break;
+        }
+        if *freq == 0 {
+            *freq = 1;
+            used += 1;
+        }
+    }
+}
+
+fn fixed_literal_lengths() -> Vec<u8> {
+    (0 .. 288).map(|i| if i < 144 { 8 } else if i < 256 { 9 } else if i < 280 { 7 } else { 8 }).collect()
+}
+
+fn length_code(length: usize) -> usize {
+    LENGTH_CODE_INTERPRETATION.iter().rposition(|&(base, _)| base <= length).unwrap()
+}
+
+fn distance_code(distance: usize) -> usize {
+    DISTANCE_CODE_INTERPRETATION.partition_point(|&(base, _)| base <= distance) - 1
+}
+
+#[derive(Copy, Clone)]
+enum Symbol {
+    Literal(u8),
+    // Length and distance, with their codes
+    Match(u16, u16, u8, u8),
+}
+
+impl Symbol {
+    fn new_match(length: usize, distance: usize) -> Symbol {
+        Symbol::Match(length as u16, distance as u16, length_code(length) as u8, distance_code(distance) as u8)
+    }
+}
+
+// Run-length encodes the code lengths of a dynamic block with the code length alphabet,
+// as (symbol, extra bits value) pairs
+fn encode_code_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let run = lengths[i ..].iter().take_while(|&&l| l == len).count();
+        if len == 0 && run >= 11 {
+            let run = usize::min(run, 138);
+            encoded.push((18, (run - 11) as u8));
+            i += run;
+        } else if len == 0 && run >= 3 {
+            encoded.push((17, (run - 3) as u8));
+            i += run;
+        } else if len != 0 && run >= 4 {
+            // The first length is written out, and the repeat code copies it
+            encoded.push((len, 0));
+            let run = usize::min(run - 1, 6);
+            encoded.push((16, (run - 3) as u8));
+            i += run + 1;
+        } else {
+            encoded.push((len, 0));
+            i += 1;
+        }
+    }
+    encoded
+}
+
+const CODE_LENGTH_EXTRA_BITS: [u8; 3] = [2, 3, 7];
+
+// Everything needed to write a dynamic block, computed up front to compare its size with the other block types
+struct DynamicHeader {
+    literal_lengths: Vec<u8>,
+    distance_lengths: Vec<u8>,
+    code_length_lengths: Vec<u8>,
+    encoded_lengths: Vec<(u8, u8)>,
+    code_lengths_num: usize,
+}
+
+impl DynamicHeader {
+    fn new(literal_freqs: &[u32], distance_freqs: &[u32]) -> DynamicHeader {
+        let mut literal_freqs = literal_freqs.to_vec();
+        let mut distance_freqs = distance_freqs.to_vec();
+        ensure_two_symbols(&mut literal_freqs);
+        ensure_two_symbols(&mut distance_freqs);
+        let mut literal_lengths = code_lengths(&literal_freqs, MAX_CODE_LENGTH);
+        let mut distance_lengths = code_lengths(&distance_freqs, MAX_CODE_LENGTH);
+        let literals_num = usize::max(257, literal_lengths.iter().rposition(|&len| len > 0).unwrap() + 1);
+        let distances_num = distance_lengths.iter().rposition(|&len| len > 0).unwrap() + 1;
+        literal_lengths.truncate(literals_num);
+        distance_lengths.truncate(distances_num);
+        let all_lengths: Vec<u8> = literal_lengths.iter().chain(&distance_lengths).copied().collect();
+        let encoded_lengths = encode_code_lengths(&all_lengths);
+        let mut code_length_freqs = [0; 19];
+        for &(symbol, _) in &encoded_lengths {
+            code_length_freqs[symbol as usize] += 1;
+        }
+        ensure_two_symbols(&mut code_length_freqs);
+        let code_length_lengths = code_lengths(&code_length_freqs, MAX_CODE_LENGTH_CODE_LENGTH);
+        let code_lengths_num = usize::max(4, CODE_LENGTH_ORDER.iter().rposition(|&symbol| code_length_lengths[symbol as usize] > 0).unwrap() + 1);
+        DynamicHeader { literal_lengths, distance_lengths, code_length_lengths, encoded_lengths, code_lengths_num }
+    }
+
+    fn size(&self) -> usize {
+        let encoded: usize = self.encoded_lengths.iter().map(|&(symbol, _)| {
+            self.code_length_lengths[symbol as usize] as usize + if symbol >= 16 { CODE_LENGTH_EXTRA_BITS[symbol as usize - 16] as usize } else { 0 }
+        }).sum();
+        5 + 5 + 4 + self.code_lengths_num * 3 + encoded
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits(self.literal_lengths.len() as u32 - 257, 5);
+        writer.write_bits(self.distance_lengths.len() as u32 - 1, 5);
+        writer.write_bits(self.code_lengths_num as u32 - 4, 4);
+        for &symbol in &CODE_LENGTH_ORDER[.. self.code_lengths_num] {
+            writer.write_bits(self.code_length_lengths[symbol as usize] as u32, 3);
+        }
+        let codes = canonical_codes(&self.code_length_lengths);
+        for &(symbol, extra) in &self.encoded_lengths {
+            writer.write_bits(codes[symbol as usize] as u32, self.code_length_lengths[symbol as usize]);
+            if symbol >= 16 {
+                writer.write_bits(extra as u32, CODE_LENGTH_EXTRA_BITS[symbol as usize - 16]);
+            }
+        }
+    }
+}
+
+// Size in bits of the symbols of a block with the given code lengths, including the end of block code
+fn symbols_size(literal_freqs: &[u32], distance_freqs: &[u32], literal_lengths: &[u8], distance_lengths: &[u8]) -> usize {
+    let mut size = 0;
+    for (symbol, &freq) in literal_freqs.iter().enumerate() {
+        if freq > 0 {
+            let extra = if symbol > END_OF_BLOCK { LENGTH_CODE_INTERPRETATION[symbol - 257].1 } else { 0 };
+            size += freq as usize * (literal_lengths[symbol] + extra) as usize;
+        }
+    }
+    for (symbol, &freq) in distance_freqs.iter().enumerate() {
+        if freq > 0 {
+            size += freq as usize * (distance_lengths[symbol] + DISTANCE_CODE_INTERPRETATION[symbol].1) as usize;
+        }
+    }
+    size
+}
+
+fn write_symbols(writer: &mut BitWriter, symbols: &[Symbol], literal_lengths: &[u8], distance_lengths: &[u8]) {
+    let literal_codes = canonical_codes(literal_lengths);
+    let distance_codes = canonical_codes(distance_lengths);
+    for &symbol in symbols {
+        match symbol {
+            Symbol::Literal(byte) => writer.write_bits(literal_codes[byte as usize] as u32, literal_lengths[byte as usize]),
+            Symbol::Match(length, distance, length_code, distance_code) => {
+                let literal = 257 + length_code as usize;
+                writer.write_bits(literal_codes[literal] as u32, literal_lengths[literal]);
+                let (base_length, length_extra_bits) = LENGTH_CODE_INTERPRETATION[length_code as usize];
+                writer.write_bits((length as usize - base_length) as u32, length_extra_bits);
+                writer.write_bits(distance_codes[distance_code as usize] as u32, distance_lengths[distance_code as usize]);
+                let (base_distance, distance_extra_bits) = DISTANCE_CODE_INTERPRETATION[distance_code as usize];
+                writer.write_bits((distance as usize - base_distance) as u32, distance_extra_bits);
+            },
+        }
+    }
+    writer.write_bits(literal_codes[END_OF_BLOCK] as u32, literal_lengths[END_OF_BLOCK]);
+}
+
+fn write_stored_blocks(writer: &mut BitWriter, data: &[u8], last: bool) {
+    let mut blocks = data.chunks(MAX_STORED_LEN).peekable();
+    if blocks.peek().is_none() {
+        writer.write_bits(last as u32, 3);
+        writer.align();
+        writer.out.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        writer.write_bits((last && blocks.peek().is_none()) as u32, 3);
+        writer.align();
+        writer.out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        writer.out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        writer.out.extend_from_slice(block);
+    }
+}
+
+// Writes the symbols covering the data as whichever of a stored, fixed or dynamic block is smallest
+fn write_block(writer: &mut BitWriter, symbols: &[Symbol], data: &[u8], last: bool) {
+    let mut literal_freqs = [0; 286];
+    let mut distance_freqs = [0; 30];
+    literal_freqs[END_OF_BLOCK] = 1;
+    for &symbol in symbols {
+        match symbol {
+            Symbol::Literal(byte) => literal_freqs[byte as usize] += 1,
+            Symbol::Match(_, _, length_code, distance_code) => {
+                literal_freqs[257 + length_code as usize] += 1;
+                distance_freqs[distance_code as usize] += 1;
+            },
+        }
+    }
+    let fixed_literal_lengths = fixed_literal_lengths();
+    let fixed_distance_lengths = [5; 30];
+    let fixed_size = 3 + symbols_size(&literal_freqs, &distance_freqs, &fixed_literal_lengths, &fixed_distance_lengths);
+    let header = DynamicHeader::new(&literal_freqs, &distance_freqs);
+    let dynamic_size = 3 + header.size() + symbols_size(&literal_freqs, &distance_freqs, &header.literal_lengths, &header.distance_lengths);
+    let stored_blocks = usize::max(1, data.len().div_ceil(MAX_STORED_LEN));
+    // Each stored block is padded to a byte boundary, which takes at most 7 bits for the first one
+    let stored_size = stored_blocks * (3 + 32) + 7 + data.len() * 8;
+    if stored_size <= fixed_size && stored_size <= dynamic_size {
+        write_stored_blocks(writer, data, last);
+    } else if fixed_size <= dynamic_size {
+        writer.write_bits(last as u32 | (1 << 1), 3);
+        write_symbols(writer, symbols, &fixed_literal_lengths, &fixed_distance_lengths);
+    } else {
+        writer.write_bits(last as u32 | (2 << 1), 3);
+        header.write(writer);
+        write_symbols(writer, symbols, &header.literal_lengths, &header.distance_lengths);
+    }
+}
+
+// Finds earlier occurrences of the data at each position through chains of positions with the same hash of their first bytes
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    // Most recent position plus one with each hash, or zero if there is none
+    head: Vec<usize>,
+    // Previous position plus one with the same hash as each position in the window
+    prev: Vec<usize>,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8]) -> MatchFinder<'a> {
+        MatchFinder { data, head: vec![0; 1 << HASH_BITS], prev: vec![0; WINDOW_SIZE] }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let bytes = ((self.data[pos] as u32) << 16) | ((self.data[pos + 1] as u32) << 8) | self.data[pos + 2] as u32;
+        (bytes.wrapping_mul(0x9E3779B1) >> (32 - HASH_BITS)) as usize
+    }
+
+    // Adds the position to the chains, which must be done in order for each position after searching from it
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let hash = self.hash(pos);
+            self.prev[pos % WINDOW_SIZE] = self.head[hash];
+            self.head[hash] = pos + 1;
+        }
+    }
+
+    // Returns the length and distance of the longest match at the position, if one of at least MIN_MATCH bytes is found
+    fn find(&self, pos: usize, max_chain: usize, nice_length: usize) -> Option<(usize, usize)> {
+        let max_length = usize::min(MAX_MATCH, self.data.len() - pos);
+        if max_length < MIN_MATCH {
+            return None;
+        }
+        let current = &self.data[pos .. pos + max_length];
+        let mut best = None;
+        let mut best_length = MIN_MATCH - 1;
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain = max_chain;
+        while candidate > 0 && chain > 0 {
+            let start = candidate - 1;
+            if pos - start > WINDOW_SIZE {
+                break;
+            }
+            let earlier = &self.data[start .. start + max_length];
+            if earlier[best_length] == current[best_length] {
+                let length = earlier.iter().zip(current).take_while(|(a, b)| a == b).count();
+                if length > best_length {
+                    best_length = length;
+                    best = Some((length, pos - start));
+                    if length >= nice_length || length == max_length {
+                        break;
+                    }
+                }
+            }
+            let next = self.prev[start % WINDOW_SIZE];
+            // Entries overwritten by later positions would not lead further back
+            if next >= candidate {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        match best {
+            Some((MIN_MATCH, distance)) if distance > TOO_FAR => None,
+            best => best,
+        }
+    }
+}
+
+// Splits the data into literals and matches, looking for a longer match at the next position before taking one if lazy
+fn find_symbols<F>(data: &[u8], config: &LevelConfig, mut on_block: F) where F: FnMut(&[Symbol], usize) {
+    let mut finder = MatchFinder::new(data);
+    let mut symbols = Vec::with_capacity(BLOCK_SYMBOLS);
+    let mut pending = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let found = match pending.take() {
+            Some(found) => found,
+            None => finder.find(pos, config.max_chain, config.nice_length),
+        };
+        finder.insert(pos);
+        match found {
+            None => {
+                symbols.push(Symbol::Literal(data[pos]));
+                pos += 1;
+            },
+            Some((length, distance)) => {
+                if config.lazy && length < config.max_lazy {
+                    let max_chain = if length >= config.good_length { config.max_chain >> 2 } else { config.max_chain };
+                    let next = finder.find(pos + 1, max_chain, config.nice_length);
+                    if let Some((next_length, _)) = next {
+                        if next_length > length {
+                            symbols.push(Symbol::Literal(data[pos]));
+                            pos += 1;
+                            pending = Some(next);
+                            continue;
+                        }
+                    }
+                }
+                symbols.push(Symbol::new_match(length, distance));
+                for i in pos + 1 .. pos + length {
+                    finder.insert(i);
+                }
+                pos += length;
+            },
+        }
+        if symbols.len() >= BLOCK_SYMBOLS && pos < data.len() {
+            on_block(&symbols, pos);
+            symbols.clear();
+        }
+    }
+    on_block(&symbols, pos);
+}
+
+// Compresses the data into a zlib stream at a level from 0 (stored blocks only) to 9 (best compression)
+pub fn write_zlib(data: &[u8], level: u8) -> Vec<u8> {
+    let level = u8::min(level, MAX_COMPRESSION_LEVEL);
+    // Deflate with a 32K window, and the compression level in the same groups as zlib
+    let cmf = 0x78;
+    let flevel = match level {
+        0 | 1 => 0,
+        2 ..= 5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let mut flags = flevel << 6;
+    flags += (31 - ((cmf as u16) << 8 | flags) % 31) % 31;
+    let mut writer = BitWriter::new(Vec::with_capacity(data.len() / 2 + 64));
+    writer.out.extend_from_slice(&[cmf, flags as u8]);
+    if level == 0 {
+        write_stored_blocks(&mut writer, data, true);
+    } else {
+        let mut start = 0;
+        find_symbols(data, &LEVEL_CONFIGS[level as usize - 1], |symbols, end| {
+            write_block(&mut writer, symbols, &data[start .. end], end == data.len());
+            start = end;
+        });
+    }
+    let mut out = writer.end();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::SliceReader;
+    use crate::zlib::read_zlib;
+
+    // Deterministic pseudo-random bytes from a xorshift generator
+    fn random_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0 .. len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    fn inflate(compressed: &[u8], max_len: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        read_zlib(SliceReader::new(compressed), &mut data, max_len, &[], |_| Ok(())).unwrap();
+        data
+    }
+
+    fn round_trip(data: &[u8], level: u8) -> Vec<u8> {
+        let compressed = write_zlib(data, level);
+        assert_eq!((((compressed[0] as u16) << 8) | compressed[1] as u16) % 31, 0);
+        assert_eq!(inflate(&compressed, data.len()), data);
+        compressed
+    }
+
+    // Type of the first block, from the bits after the final block bit
+    fn first_block_type(compressed: &[u8]) -> u8 {
+        (compressed[2] >> 1) & 0x3
+    }
+
+    #[test]
+    fn all_levels() {
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(200);
+        let mut data = text.clone();
+        data.extend(random_bytes(5000, 1));
+        data.extend(&text[.. 1000]);
+        for level in 0 ..= MAX_COMPRESSION_LEVEL {
+            let compressed = round_trip(&data, level);
+            if level > 0 {
+                assert!(compressed.len() < data.len() / 2);
+            }
+        }
+    }
+
+    #[test]
+    fn empty() {
+        for level in 0 ..= MAX_COMPRESSION_LEVEL {
+            round_trip(&[], level);
+        }
+    }
+
+    #[test]
+    fn stored_blocks() {
+        let data = random_bytes(3 * MAX_STORED_LEN + 100, 2);
+        let compressed = round_trip(&data, 0);
+        // Header, four stored blocks with their lengths, and the checksum
+        assert_eq!(compressed.len(), 2 + 4 * 5 + data.len() + 4);
+    }
+
+    #[test]
+    fn long_runs() {
+        let mut data = vec![0x55; 100000];
+        data.extend(vec![0xAA; 1000]);
+        for &level in &[1, 6, 9] {
+            assert!(round_trip(&data, level).len() < 1000);
+        }
+    }
+
+    #[test]
+    fn matches_at_window_size() {
+        let block = random_bytes(WINDOW_SIZE, 3);
+        let data = [&block[..], &block[..], &block[..]].concat();
+        let mut distances = Vec::new();
+        find_symbols(&data, &LEVEL_CONFIGS[5], |symbols, _| {
+            distances.extend(symbols.iter().filter_map(|&symbol| match symbol {
+                Symbol::Match(_, distance, _, _) => Some(distance as usize),
+                Symbol::Literal(_) => None,
+            }));
+        });
+        assert!(distances.contains(&WINDOW_SIZE));
+        for &level in &[1, 6, 9] {
+            assert!(round_trip(&data, level).len() < WINDOW_SIZE + WINDOW_SIZE / 4);
+        }
+    }
+
+    #[test]
+    fn random_data() {
+        let data = random_bytes(20000, 4);
+        for level in 1 ..= MAX_COMPRESSION_LEVEL {
+            let compressed = round_trip(&data, level);
+            assert!(first_block_type(&compressed) < 2);
+        }
+    }
+
+    #[test]
+    fn multiple_dynamic_blocks() {
+        // Random letters from a small alphabet have skewed literal frequencies and few long matches
+        let data: Vec<u8> = random_bytes(4 * BLOCK_SYMBOLS, 5).iter().map(|&b| b'0' + (b & 0x3F)).collect();
+        let mut blocks = 0;
+        find_symbols(&data, &LEVEL_CONFIGS[5], |_, _| blocks += 1);
+        assert!(blocks > 1);
+        let compressed = round_trip(&data, 6);
+        assert_eq!(first_block_type(&compressed), 2);
+    }
+}
@@ -0,0 +1,254 @@
+use crate::crc::Crc;
+use crate::decoder::PNG_SIG;
+use crate::deflate;
+use crate::filter;
+use crate::filter::FilterStrategy;
+use crate::filter::FilterType;
+use crate::ihdr::ColorMode;
+use crate::ihdr::InterlaceMethod;
+use crate::trns::Transparency;
+use crate::Error;
+use crate::Result;
+use std::io::Write;
+
+// Largest amount of compressed data written to a single IDAT chunk
+const IDAT_CHUNK_SIZE: usize = 1 << 16;
+
+// Encodes an image into a PNG file from rows of packed samples,
+// laid out as in unfiltered scanlines without the filter type byte, as produced by Decoder::decode_raw
+pub struct Encoder {
+    width: u32,
+    height: u32,
+    color_mode: ColorMode,
+    interlace_method: InterlaceMethod,
+    compression_level: u8,
+    filter_strategy: FilterStrategy,
+    transparency: Option<Transparency>,
+    // Filter type of each scanline of the last encoded image
+    filter_types: Vec<FilterType>,
+}
+
+impl Encoder {
+    pub fn new(width: u32, height: u32, color_mode: ColorMode) -> Result<Encoder> {
+        if width == 0 || height == 0 {
+            return Err(Error::Format("Width or height is zero"));
+        }
+        if width > 0x7FFFFFFF || height > 0x7FFFFFFF {
+            return Err(Error::Format("Width or height exceeds (2^31)-1"));
+        }
+        if let Some(palette) = color_mode.palette() {
+            if palette.is_empty() || palette.len() > 1 << color_mode.bit_depth() {
+                return Err(Error::Format("Number of palette entries is not valid for the bit depth"));
+            }
+        }
+        // The PNG specification recommends no filtering for palette images and bit depths below 8
+        let filter_strategy = if color_mode.palette().is_some() || color_mode.bit_depth() < 8 {
+            FilterStrategy::Fixed(FilterType::None)
+        } else {
+            FilterStrategy::MinimumSum
+        };
+        Ok(Encoder {
+            width,
+            height,
+            color_mode,
+            interlace_method: InterlaceMethod::NoInterlace,
+            compression_level: deflate::DEFAULT_COMPRESSION_LEVEL,
+            filter_strategy,
+            transparency: None,
+            filter_types: Vec::new(),
+        })
+    }
+
+    pub fn set_interlace_method(&mut self, interlace_method: InterlaceMethod) {
+        self.interlace_method = interlace_method;
+    }
+
+    // Sets the compression level from 0 (no compression) to 9 (best compression), defaulting to 6.
+    // Higher levels are clamped to 9.
+    pub fn set_compression_level(&mut self, compression_level: u8) {
+        self.compression_level = compression_level;
+    }
+
+    // Sets how the filter type of each scanline is chosen.
+    // Defaults to no filtering for palette images and bit depths below 8, and to the minimum sum strategy otherwise.
+    pub fn set_filter_strategy(&mut self, filter_strategy: FilterStrategy) {
+        self.filter_strategy = filter_strategy;
+    }
+
+    // Sets the transparency written to a tRNS chunk, as returned by Decoder::info.
+    // Fails if it does not match the color mode, which for modes with an alpha channel means any transparency.
+    pub fn set_transparency(&mut self, transparency: Option<Transparency>) -> Result<()> {
+        use ColorMode::*;
+        let color_mode = &self.color_mode;
+        let valid = match transparency {
+            None => true,
+            Some(Transparency::Palette(ref alphas)) => color_mode.palette().is_some_and(|palette| alphas.len() <= palette.len()),
+            Some(Transparency::Gray(gray)) => match color_mode {
+                Grayscale1 | Grayscale2 | Grayscale4 | Grayscale8 | Grayscale16 => (gray as u32) < 1 << color_mode.bit_depth(),
+                _ => false,
+            },
+            Some(Transparency::RGB(red, green, blue)) => match color_mode {
+                RGB8 | RGB16 => (u16::max(red, u16::max(green, blue)) as u32) < 1 << color_mode.bit_depth(),
+                _ => false,
+            },
+        };
+        if !valid {
+            return Err(Error::Format("Transparency is not valid for the color mode"));
+        }
+        self.transparency = transparency;
+        Ok(())
+    }
+
+    // Returns the filter type chosen for each scanline of the last encoded image, in the order the scanlines are stored,
+    // which for Adam7 images is pass by pass
+    pub fn filter_types(&self) -> &[FilterType] {
+        &self.filter_types
+    }
+
+    // Writes the image with the samples from the buffer, in which each row starts pitch bytes after the previous one.
+    // Returns the writer, which is not flushed.
+    pub fn encode<W>(&mut self, mut file: W, samples: &[u8], pitch: usize) -> Result<W> where W: Write {
+        let bytes_per_row = self.color_mode.bytes_per_row(self.width);
+        if pitch < bytes_per_row || samples.len() < pitch * (self.height as usize - 1) + bytes_per_row {
+            return Err(Error::Format("Sample buffer is too small for the image"));
+        }
+        file.write_all(&PNG_SIG)?;
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&self.width.to_be_bytes());
+        ihdr.extend_from_slice(&self.height.to_be_bytes());
+        ihdr.push(self.color_mode.bit_depth());
+        ihdr.push(self.color_mode.color_type());
+        // Compression and filter methods
+        ihdr.extend_from_slice(&[0, 0]);
+        ihdr.push(match self.interlace_method {
+            InterlaceMethod::NoInterlace => 0,
+            InterlaceMethod::Adam7 => 1,
+        });
+        write_chunk(&mut file, b"IHDR", &ihdr)?;
+        if let Some(palette) = self.color_mode.palette() {
+            let plte: Vec<u8> = palette.iter().flat_map(|&(r, g, b)| vec![r, g, b]).collect();
+            write_chunk(&mut file, b"PLTE", &plte)?;
+        }
+        if let Some(ref transparency) = self.transparency {
+            let trns = match *transparency {
+                Transparency::Palette(ref alphas) => alphas.to_vec(),
+                Transparency::Gray(gray) => gray.to_be_bytes().to_vec(),
+                Transparency::RGB(red, green, blue) => [red, green, blue].iter().flat_map(|v| v.to_be_bytes().to_vec()).collect(),
+            };
+            write_chunk(&mut file, b"tRNS", &trns)?;
+        }
+        let (scanlines, filter_types) = filter::filter_scanlines(samples, pitch, self.width, self.height, &self.color_mode, self.interlace_method, self.filter_strategy, self.compression_level);
+        self.filter_types = filter_types;
+        let data = deflate::write_zlib(&scanlines, self.compression_level);
+        for idat in data.chunks(IDAT_CHUNK_SIZE) {
+            write_chunk(&mut file, b"IDAT", idat)?;
+        }
+        write_chunk(&mut file, b"IEND", &[])?;
+        Ok(file)
+    }
+}
+
+pub fn write_chunk<W>(file: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> Result<()> where W: Write {
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    file.write_all(&(data.len() as u32).to_be_bytes())?;
+    file.write_all(chunk_type)?;
+    file.write_all(data)?;
+    file.write_all(&crc.value().to_be_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::test_color_modes;
+    use crate::filter::test_palette;
+    use crate::filter::test_samples;
+    use crate::Decoder;
+    use crate::ImageInfo;
+    use std::io::Cursor;
+
+    const WIDTHS: [u32; 4] = [1, 3, 7, 9];
+    const HEIGHTS: [u32; 3] = [1, 5, 9];
+
+    fn decode_raw(png: Vec<u8>, samples: &mut [u8], pitch: usize) -> Result<ImageInfo> {
+        Decoder::new(Cursor::new(png))?.decode_raw(samples, pitch)
+    }
+
+    fn round_trip(width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod) {
+        let (samples, pitch) = test_samples(width, height, color_mode);
+        let mut encoder = Encoder::new(width, height, color_mode.clone()).unwrap();
+        encoder.set_interlace_method(interlace_method);
+        let png = encoder.encode(Vec::new(), &samples, pitch).unwrap();
+        let mut decoded = vec![0; samples.len()];
+        let info = decode_raw(png, &mut decoded, pitch).unwrap();
+        assert_eq!((info.width, info.height), (width, height));
+        assert_eq!((info.color_mode.color_type(), info.color_mode.bit_depth()), (color_mode.color_type(), color_mode.bit_depth()));
+        assert_eq!(info.color_mode.palette(), color_mode.palette());
+        let bytes_per_row = color_mode.bytes_per_row(width);
+        for (y, (row, decoded_row)) in samples.chunks(pitch).zip(decoded.chunks(pitch)).enumerate() {
+            assert_eq!(row[.. bytes_per_row], decoded_row[.. bytes_per_row],
+                "color type {}, bit depth {}, {}x{}, row {}", color_mode.color_type(), color_mode.bit_depth(), width, height, y);
+        }
+    }
+
+    #[test]
+    fn round_trip_without_interlacing() {
+        for color_mode in &test_color_modes() {
+            for &width in &WIDTHS {
+                for &height in &HEIGHTS {
+                    round_trip(width, height, color_mode, InterlaceMethod::NoInterlace);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_adam7() {
+        for color_mode in &test_color_modes() {
+            for &width in &WIDTHS {
+                for &height in &HEIGHTS {
+                    round_trip(width, height, color_mode, InterlaceMethod::Adam7);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_transparency() {
+        let cases = vec![
+            (ColorMode::Palette4(test_palette(16)), Transparency::Palette(vec![0, 128, 255].into_boxed_slice())),
+            (ColorMode::Grayscale2, Transparency::Gray(3)),
+            (ColorMode::Grayscale16, Transparency::Gray(0xBEEF)),
+            (ColorMode::RGB8, Transparency::RGB(1, 2, 3)),
+            (ColorMode::RGB16, Transparency::RGB(0x1234, 0x5678, 0x9ABC)),
+        ];
+        for (color_mode, transparency) in cases {
+            let (samples, pitch) = test_samples(3, 3, &color_mode);
+            let mut encoder = Encoder::new(3, 3, color_mode).unwrap();
+            encoder.set_transparency(Some(transparency.clone())).unwrap();
+            let png = encoder.encode(Vec::new(), &samples, pitch).unwrap();
+            let info = decode_raw(png, &mut vec![0; samples.len()], pitch).unwrap();
+            assert_eq!(info.transparency, Some(transparency));
+        }
+    }
+
+    #[test]
+    fn transparency_not_matching_color_mode() {
+        let mut encoder = Encoder::new(1, 1, ColorMode::RGB8).unwrap();
+        assert!(encoder.set_transparency(Some(Transparency::Gray(0))).is_err());
+        assert!(encoder.set_transparency(Some(Transparency::RGB(0, 0, 256))).is_err());
+        assert!(encoder.set_transparency(Some(Transparency::RGB(0, 0, 255))).is_ok());
+        assert!(encoder.set_transparency(None).is_ok());
+        let mut encoder = Encoder::new(1, 1, ColorMode::Palette2(test_palette(3))).unwrap();
+        assert!(encoder.set_transparency(Some(Transparency::Palette(vec![0; 4].into_boxed_slice()))).is_err());
+        assert!(encoder.set_transparency(Some(Transparency::Palette(vec![0; 3].into_boxed_slice()))).is_ok());
+        assert!(encoder.set_transparency(Some(Transparency::Gray(0))).is_err());
+        let mut encoder = Encoder::new(1, 1, ColorMode::Grayscale4).unwrap();
+        assert!(encoder.set_transparency(Some(Transparency::Gray(16))).is_err());
+        assert!(encoder.set_transparency(Some(Transparency::Gray(15))).is_ok());
+        let mut encoder = Encoder::new(1, 1, ColorMode::RGBA8).unwrap();
+        assert!(encoder.set_transparency(Some(Transparency::RGB(0, 0, 0))).is_err());
+    }
+}
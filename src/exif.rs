@@ -0,0 +1,196 @@
+use crate::chunk::ChunkReader;
+use crate::Result;
+use std::io::Read;
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_X_RESOLUTION: u16 = 0x011A;
+const TAG_Y_RESOLUTION: u16 = 0x011B;
+const TAG_RESOLUTION_UNIT: u16 = 0x0128;
+const TAG_DATE_TIME: u16 = 0x0132;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+// How the stored image has to be transformed to be displayed upright
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    Normal,
+    MirrorHorizontal,
+    Rotate180,
+    MirrorVertical,
+    // Mirrored along the top-left to bottom-right diagonal
+    Transpose,
+    Rotate90,
+    // Mirrored along the top-right to bottom-left diagonal
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    // Whether the width and height are swapped when displayed
+    pub fn swaps_axes(&self) -> bool {
+        matches!(self, Orientation::Transpose | Orientation::Rotate90 | Orientation::Transverse | Orientation::Rotate270)
+    }
+
+    // Maps the position of a pixel in the stored image of the given size to its position when displayed
+    pub fn apply(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Orientation::Normal => (x, y),
+            Orientation::MirrorHorizontal => (width - 1 - x, y),
+            Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Orientation::MirrorVertical => (x, height - 1 - y),
+            Orientation::Transpose => (y, x),
+            Orientation::Rotate90 => (height - 1 - y, x),
+            Orientation::Transverse => (height - 1 - y, width - 1 - x),
+            Orientation::Rotate270 => (y, width - 1 - x),
+        }
+    }
+}
+
+// Commonly used tags from the first IFD of the EXIF data.
+// Resolutions are rationals as (numerator, denominator).
+#[derive(Clone, Debug, Default)]
+pub struct ExifTags {
+    pub orientation: Option<Orientation>,
+    pub date_time: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub x_resolution: Option<(u32, u32)>,
+    pub y_resolution: Option<(u32, u32)>,
+    pub resolution_unit: Option<u16>,
+}
+
+// EXIF data from an eXIf chunk, with the tags that could be parsed from it
+pub struct Exif {
+    pub data: Box<[u8]>,
+    pub tags: ExifTags,
+}
+
+// Reads integers from TIFF data in its byte order, returning None when reading past the end
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?];
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?, *self.data.get(offset + 2)?, *self.data.get(offset + 3)?];
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    // Offset of the value of an IFD entry, which is stored in the entry itself if it fits in 4 bytes
+    fn value_offset(&self, entry: usize, value_size: usize) -> Option<usize> {
+        if value_size <= 4 {
+            Some(entry + 8)
+        } else {
+            Some(self.u32_at(entry + 8)? as usize)
+        }
+    }
+
+    fn ascii(&self, entry: usize, count: usize) -> Option<String> {
+        let offset = self.value_offset(entry, count)?;
+        let bytes = self.data.get(offset .. offset.checked_add(count)?)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Some(String::from_utf8_lossy(&bytes[.. end]).into_owned())
+    }
+
+    fn short(&self, entry: usize, data_type: u16) -> Option<u16> {
+        match data_type {
+            TYPE_SHORT => self.u16_at(entry + 8),
+            TYPE_LONG => self.u32_at(entry + 8).map(|v| v as u16),
+            _ => None,
+        }
+    }
+
+    fn rational(&self, entry: usize) -> Option<(u32, u32)> {
+        let offset = self.value_offset(entry, 8)?;
+        Some((self.u32_at(offset)?, self.u32_at(offset + 4)?))
+    }
+}
+
+fn parse_tiff(data: &[u8]) -> Option<ExifTags> {
+    let big_endian = match data.get(0 .. 2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let tiff = Tiff { data, big_endian };
+    if tiff.u16_at(2)? != 42 {
+        return None;
+    }
+    let ifd = tiff.u32_at(4)? as usize;
+    let count = tiff.u16_at(ifd)? as usize;
+    let mut tags = ExifTags::default();
+    for i in 0 .. count {
+        let entry = ifd + 2 + i * 12;
+        let tag = tiff.u16_at(entry)?;
+        let data_type = tiff.u16_at(entry + 2)?;
+        let value_count = tiff.u32_at(entry + 4)? as usize;
+        match (tag, data_type) {
+            (TAG_ORIENTATION, _) => {
+                tags.orientation = match tiff.short(entry, data_type) {
+                    Some(1) => Some(Orientation::Normal),
+                    Some(2) => Some(Orientation::MirrorHorizontal),
+                    Some(3) => Some(Orientation::Rotate180),
+                    Some(4) => Some(Orientation::MirrorVertical),
+                    Some(5) => Some(Orientation::Transpose),
+                    Some(6) => Some(Orientation::Rotate90),
+                    Some(7) => Some(Orientation::Transverse),
+                    Some(8) => Some(Orientation::Rotate270),
+                    _ => {
+                        warn!("Invalid EXIF orientation");
+                        None
+                    },
+                };
+            },
+            (TAG_DATE_TIME, TYPE_ASCII) => tags.date_time = tiff.ascii(entry, value_count),
+            (TAG_MAKE, TYPE_ASCII) => tags.make = tiff.ascii(entry, value_count),
+            (TAG_MODEL, TYPE_ASCII) => tags.model = tiff.ascii(entry, value_count),
+            (TAG_X_RESOLUTION, TYPE_RATIONAL) => tags.x_resolution = tiff.rational(entry),
+            (TAG_Y_RESOLUTION, TYPE_RATIONAL) => tags.y_resolution = tiff.rational(entry),
+            (TAG_RESOLUTION_UNIT, _) => tags.resolution_unit = tiff.short(entry, data_type),
+            _ => (),
+        }
+    }
+    Some(tags)
+}
+
+pub fn load_exif<R>(chunk: &mut ChunkReader<R>) -> Result<Option<Exif>> where R: Read {
+    let data = chunk.read_rest()?.into_boxed_slice();
+    info!("EXIF data size: {}", data.len());
+    let tags = match parse_tiff(&data) {
+        Some(tags) => tags,
+        None => {
+            warn!("eXIf chunk does not contain valid TIFF data");
+            ExifTags::default()
+        },
+    };
+    if let Some(orientation) = tags.orientation {
+        info!("Orientation: {:?}", orientation);
+    }
+    if let Some(date_time) = &tags.date_time {
+        info!("Date and time: {}", date_time);
+    }
+    if let Some(make) = &tags.make {
+        info!("Make: {}", make);
+    }
+    if let Some(model) = &tags.model {
+        info!("Model: {}", model);
+    }
+    if let (Some(x), Some(y)) = (tags.x_resolution, tags.y_resolution) {
+        info!("Resolution: {}/{} x {}/{}", x.0, x.1, y.0, y.1);
+    }
+    if let Some(unit) = tags.resolution_unit {
+        info!("Resolution unit: {}", unit);
+    }
+    Ok(Some(Exif { data, tags }))
+}
@@ -1,88 +1,171 @@
+use crate::Error;
 use crate::Result;
 use std::io::Read;
-use std::fs::File;
 
 pub trait ByteReader {
-    fn read_buf(&mut self, len: u32) -> Result<Box<[u8]>>;
+    // Fills the whole buffer.
+    // If the data ends earlier, returns EndOfChunk with the number of bytes that were read into the buffer.
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<()>;
+
+    // Reads at most as many bytes as fit in the buffer, returning fewer if reading more
+    // would require going past the end of the current chunk
+    fn read_available(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.read_buf(buf)?;
+        Ok(buf.len())
+    }
 
     fn read_u8(&mut self) -> Result<u8> {
-        let buf = self.read_buf(1)?;
+        let mut buf = [0; 1];
+        self.read_buf(&mut buf)?;
         Ok(buf[0])
     }
 
+    #[allow(clippy::identity_op)]
     fn read_u16(&mut self) -> Result<u16> {
-        let buf = self.read_buf(2)?;
+        let mut buf = [0; 2];
+        self.read_buf(&mut buf)?;
         Ok(((buf[0] as u16) << 8) | ((buf[1] as u16) << 0))
     }
 
+    #[allow(clippy::identity_op)]
     fn read_u32(&mut self) -> Result<u32> {
-        let buf = self.read_buf(4)?;
+        let mut buf = [0; 4];
+        self.read_buf(&mut buf)?;
         Ok(((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) | ((buf[2] as u32) << 8) | ((buf[3] as u32) << 0))
     }
 }
 
-impl ByteReader for File {
-    fn read_buf(&mut self, len: u32) -> Result<Box<[u8]>> {
-        let mut buf = vec![0; len as usize].into_boxed_slice();
-        self.read_exact(&mut buf)?;
-        Ok(buf)
+impl<R> ByteReader for R where R: Read {
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+// Reads from data already in memory, such as the rest of a chunk, ending like a chunk when the data runs out
+pub struct SliceReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(data: &'a [u8]) -> SliceReader<'a> {
+        SliceReader { data }
+    }
+}
+
+impl ByteReader for SliceReader<'_> {
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<()> {
+        let len = usize::min(buf.len(), self.data.len());
+        buf[.. len].copy_from_slice(&self.data[.. len]);
+        self.data = &self.data[len ..];
+        if len < buf.len() {
+            return Err(Error::EndOfChunk(len));
+        }
+        Ok(())
+    }
+
+    fn read_available(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = usize::min(buf.len(), self.data.len());
+        self.read_buf(&mut buf[.. len])?;
+        Ok(len)
     }
 }
 
+// Reads bits starting from the least significant bit of each byte.
+// Up to 64 bits are read ahead into a buffer, so that up to 32 bits can be peeked at before consuming them.
 pub struct BitReader<T> where T: ByteReader {
     reader: T,
-    byte: u8,
+    bits: u64,
     bits_left: u8,
 }
 
 impl<T> BitReader<T> where T: ByteReader {
     pub fn new(reader: T) -> BitReader<T> {
-        BitReader { reader, byte: 0, bits_left: 0 }
+        BitReader { reader, bits: 0, bits_left: 0 }
     }
 
     pub fn end(self) -> T {
         self.reader
     }
 
-    pub fn read_bit(&mut self) -> Result<bool> {
-        if self.bits_left == 0 {
-            self.byte = self.reader.read_u8()?;
-            self.bits_left = 8;
-        }
-        let bit = self.byte & 1 != 0;
-        self.byte >>= 1;
-        self.bits_left -= 1;
-        Ok(bit)
+    fn push_byte(&mut self, byte: u8) {
+        self.bits |= (byte as u64) << self.bits_left;
+        self.bits_left += 8;
     }
 
-    pub fn read_bits(&mut self, len: u8) -> Result<u16> {
+    // Makes sure the buffer has at least len bits, unless the data ends earlier.
+    // The buffer is filled with as many bytes as are available in the current chunk at once,
+    // and bytes from the following chunks are only read once they are needed,
+    // so that the reader never goes past the end of the data it reads from.
+    fn fill(&mut self, len: u8) -> Result<()> {
         if self.bits_left >= len {
-            let bits = self.byte & ((1 << len) - 1);
-            self.bits_left -= len;
-            self.byte = u8::wrapping_shr(self.byte, len as u32);
-            Ok(bits as u16)
-        } else if self.bits_left + 8 >= len {
-            let bits = self.byte as u16;
-            let byte = self.reader.read_u8()?;
-            let bits = (bits | ((byte as u16) << self.bits_left)) & ((1 << len) - 1);
-            self.byte = u8::wrapping_shr(byte, (len - self.bits_left) as u32);
-            self.bits_left = self.bits_left + 8 - len;
-            Ok(bits)
-        } else {
-            let bits = self.byte as u16;
-            let byte1 = self.reader.read_u8()?;
-            let byte2 = self.reader.read_u8()?;
-            let bits = (bits | ((byte1 as u16) << self.bits_left) | ((byte2 as u16) << (8 + self.bits_left))) & ((1 << len) - 1);
-            self.byte = u8::wrapping_shr(byte2, (len - self.bits_left - 8) as u32);
-            self.bits_left = self.bits_left + 16 - len;
-            Ok(bits)
+            return Ok(());
+        }
+        let mut data = [0; 8];
+        let available = self.reader.read_available(&mut data[.. ((64 - self.bits_left) / 8) as usize])?;
+        for &byte in &data[.. available] {
+            self.push_byte(byte);
         }
+        while self.bits_left < len {
+            match self.reader.read_u8() {
+                Ok(byte) => self.push_byte(byte),
+                Err(Error::EndOfChunk(_)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the next len bits (at most 32) without consuming them.
+    // If the data ends earlier, the missing bits are zero.
+    pub fn peek_bits(&mut self, len: u8) -> Result<u32> {
+        self.fill(len)?;
+        Ok((self.bits & ((1 << len) - 1)) as u32)
+    }
+
+    pub fn consume_bits(&mut self, len: u8) -> Result<()> {
+        if len > self.bits_left {
+            return Err(Error::Format("Unexpected end of compressed data"));
+        }
+        self.bits >>= len;
+        self.bits_left -= len;
+        Ok(())
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool> {
+        Ok(self.read_bits(1)? != 0)
+    }
+
+    pub fn read_bits(&mut self, len: u8) -> Result<u32> {
+        let bits = self.peek_bits(len)?;
+        self.consume_bits(len)?;
+        Ok(bits)
+    }
+
+    // Skips the remaining bits of a partially read byte
+    pub fn align(&mut self) {
+        self.bits >>= self.bits_left % 8;
+        self.bits_left -= self.bits_left % 8;
     }
 }
 
 impl<T> ByteReader for BitReader<T> where T: ByteReader {
-    fn read_buf(&mut self, len: u32) -> Result<Box<[u8]>> {
-        self.bits_left = 0;
-        self.reader.read_buf(len)
+    // Skips to the next byte boundary, then reads the bytes that were read ahead before reading any new ones
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.align();
+        let mut i = 0;
+        while i < buf.len() && self.bits_left > 0 {
+            buf[i] = self.bits as u8;
+            self.bits >>= 8;
+            self.bits_left -= 8;
+            i += 1;
+        }
+        if i < buf.len() {
+            match self.reader.read_buf(&mut buf[i ..]) {
+                Err(Error::EndOfChunk(len)) => return Err(Error::EndOfChunk(i + len)),
+                result => return result,
+            }
+        }
+        Ok(())
     }
 }
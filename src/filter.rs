@@ -1,11 +1,16 @@
+use crate::color::ColorTransform;
+use crate::deflate;
+use crate::gamma::GammaTables;
 use crate::ihdr::ColorMode;
 use crate::ihdr::InterlaceMethod;
 use crate::ihdr::Palette;
+use crate::sbit::SignificantBits;
+use crate::trns::Transparency;
 use crate::Error;
 use crate::Result;
 
-#[derive(Copy, Clone)]
-enum FilterType {
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FilterType {
     None,
     Sub,
     Up,
@@ -13,7 +18,22 @@ enum FilterType {
     Paeth,
 }
 
+// How the encoder chooses the filter type of each scanline
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FilterStrategy {
+    // The same filter type for every scanline
+    Fixed(FilterType),
+    // The filter type minimizing the sum of the filtered bytes taken as signed values, as suggested by the PNG specification
+    MinimumSum,
+    // The filter type minimizing the Shannon entropy of the filtered bytes
+    Entropy,
+    // The filter type for which the scanline compresses best following the previous one
+    BruteForce,
+}
+
 impl FilterType {
+    const ALL: [FilterType; 5] = [FilterType::None, FilterType::Sub, FilterType::Up, FilterType::Average, FilterType::Paeth];
+
     fn read(filter_type: u8) -> Result<FilterType> {
         use FilterType::*;
         match filter_type {
@@ -25,117 +45,692 @@ impl FilterType {
             _ => Err(Error::Format("Invalid filter type")),
         }
     }
+
+    // Predicts a byte from the bytes to its left (a), above (b) and above left (c)
+    fn predict(self, a: u8, b: u8, c: u8) -> u8 {
+        match self {
+            FilterType::None => 0,
+            FilterType::Sub => a,
+            FilterType::Up => b,
+            FilterType::Average => ((a as u16 + b as u16) / 2) as u8,
+            FilterType::Paeth => {
+                let (a, b, c) = (a as i16, b as i16, c as i16);
+                let p = a + b - c;
+                let pa = i16::abs(p - a);
+                let pb = i16::abs(p - b);
+                let pc = i16::abs(p - c);
+                (if pa <= pb && pa <= pc {a} else if pb <= pc {b} else {c}) as u8
+            },
+        }
+    }
+}
+
+// Settings for converting samples to 8-bit RGBA output.
+// The color transform, if present, supersedes gamma correction.
+pub struct Conversion<'a> {
+    pub transparency: Option<&'a Transparency>,
+    pub gamma: Option<&'a GammaTables>,
+    pub color_transform: Option<&'a ColorTransform>,
+    pub significant_bits: Option<&'a SignificantBits>,
+}
+
+fn color_8(sample: u8, gamma: Option<&GammaTables>) -> u8 {
+    match gamma {
+        Some(gamma) => gamma.correct_8(sample),
+        None => sample,
+    }
+}
+
+fn color_16_to_8(sample: u16, gamma: Option<&GammaTables>) -> u8 {
+    match gamma {
+        Some(gamma) => gamma.correct_16(sample),
+        None => (sample as f64 * 255.0 / 65535.0).round() as u8,
+    }
+}
+
+// Maps a sample with only the given number of significant bits to the full range of the bit depth
+fn rescale(sample: u16, bit_depth: u8, significant_bits: u8) -> u16 {
+    if significant_bits >= bit_depth {
+        return sample;
+    }
+    let max = (1 << bit_depth) - 1;
+    let significant_max = (1 << significant_bits) - 1;
+    let value = sample as u32 >> (bit_depth - significant_bits);
+    ((value * max + significant_max / 2) / significant_max) as u16
+}
+
+// Converts samples of the given bit depth to 8-bit output colors
+#[allow(clippy::identity_op)]
+fn rgb_to_8((r, g, b): (u16, u16, u16), bit_depth: u8, conversion: &Conversion) -> (u8, u8, u8) {
+    let (r, g, b) = match conversion.significant_bits {
+        Some(significant_bits) => {
+            let (sr, sg, sb) = significant_bits.color();
+            (rescale(r, bit_depth, sr), rescale(g, bit_depth, sg), rescale(b, bit_depth, sb))
+        },
+        None => (r, g, b),
+    };
+    let scale = |sample: u16| match bit_depth {
+        1 => sample as u8 * (255 / 1),
+        2 => sample as u8 * (255 / 3),
+        4 => sample as u8 * (255 / 15),
+        _ => sample as u8,
+    };
+    match (conversion.color_transform, bit_depth) {
+        (Some(transform), 16) => transform.convert_16(r, g, b),
+        (Some(transform), _) => transform.convert_8(scale(r), scale(g), scale(b)),
+        (None, 16) => (color_16_to_8(r, conversion.gamma), color_16_to_8(g, conversion.gamma), color_16_to_8(b, conversion.gamma)),
+        (None, _) => (color_8(scale(r), conversion.gamma), color_8(scale(g), conversion.gamma), color_8(scale(b), conversion.gamma)),
+    }
 }
 
-fn color_16_to_8(upper: u8, lower: u8) -> u8 {
-    ((upper as f64 * 256.0 + lower as f64) * 255.0 / 65535.0).round() as u8
+fn gray_to_8(gray: u16, bit_depth: u8, conversion: &Conversion) -> (u8, u8, u8) {
+    rgb_to_8((gray, gray, gray), bit_depth, conversion)
 }
 
-fn palette_color(palette: &Palette, index: usize) -> Result<(u8, u8, u8, u8)> {
+fn alpha_to_8(alpha: u16, bit_depth: u8, conversion: &Conversion) -> u8 {
+    let alpha = match conversion.significant_bits.and_then(|significant_bits| significant_bits.alpha()) {
+        Some(significant_bits) => rescale(alpha, bit_depth, significant_bits),
+        None => alpha,
+    };
+    match bit_depth {
+        16 => color_16_to_8(alpha, None),
+        _ => alpha as u8,
+    }
+}
+
+fn palette_color(palette: &Palette, index: usize, conversion: &Conversion) -> Result<(u8, u8, u8, u8)> {
     if index >= palette.len() {
         return Err(Error::Format("Palette indexed past end"));
     }
-    let color = palette[index];
-    Ok((color.0, color.1, color.2, 255))
+    let (r, g, b) = palette[index];
+    let alpha = match conversion.transparency {
+        Some(Transparency::Palette(alphas)) => alphas.get(index).copied().unwrap_or(255),
+        _ => 255,
+    };
+    let (r, g, b) = rgb_to_8((r as u16, g as u16, b as u16), 8, conversion);
+    Ok((r, g, b, alpha))
 }
 
-pub fn unfilter_uninterlace(data: &mut [u8], pixels: &mut [u8], pitch: usize, width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod) -> Result<()> {
-    let width = width as usize;
-    let height = height as usize;
+// Alpha of a gray sample or RGB samples at the full bit depth, which is zero only if they match the transparent color
+fn gray_alpha(gray: u16, transparency: Option<&Transparency>) -> u8 {
+    match transparency {
+        Some(&Transparency::Gray(key)) if key == gray => 0,
+        _ => 255,
+    }
+}
+
+fn rgb_alpha(rgb: (u16, u16, u16), transparency: Option<&Transparency>) -> u8 {
+    match transparency {
+        Some(&Transparency::RGB(r, g, b)) if (r, g, b) == rgb => 0,
+        _ => 255,
+    }
+}
+
+fn sample_16(upper: u8, lower: u8) -> u16 {
+    ((upper as u16) << 8) | lower as u16
+}
+
+// (x offset, y offset, x spacing, y spacing) of each Adam7 pass
+pub const ADAM7_PASSES: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+// Size of the block of pixels approximated by each pixel of an Adam7 pass until the later passes are decoded
+const ADAM7_BLOCKS: [(usize, usize); 7] = [(8, 8), (4, 8), (4, 4), (2, 4), (2, 2), (1, 2), (1, 1)];
+
+pub fn adam7_pass_size(width: usize, height: usize, pass: usize) -> (usize, usize) {
+    let (x0, y0, dx, dy) = ADAM7_PASSES[pass];
+    ((width + dx - 1 - x0) / dx, (height + dy - 1 - y0) / dy)
+}
+
+fn pass_size(width: usize, height: usize, interlace_method: InterlaceMethod, pass: usize) -> (usize, usize) {
     match interlace_method {
-        InterlaceMethod::NoInterlace => {
-            let bits_per_pixel = color_mode.bits_per_pixel();
-            let bytes_per_scanline = (width * bits_per_pixel + 7) / 8 + 1;
-            let filter_bpp = (color_mode.bits_per_pixel() + 7) / 8;
-            for y in 0 .. height {
-                let filter_type = FilterType::read(data[y * bytes_per_scanline])?;
-                for x in 0 .. bytes_per_scanline - 1 {
-                    let a = if x >= filter_bpp { data[y * bytes_per_scanline + 1 + x - filter_bpp] as i16 } else { 0 };
-                    let b = if y > 0 { data[(y - 1) * bytes_per_scanline + 1 + x] as i16 } else { 0 };
-                    let c = if x >= filter_bpp && y > 0 { data[(y - 1) * bytes_per_scanline + 1 + x - filter_bpp] as i16 } else { 0 };
-                    data[y * bytes_per_scanline + 1 + x] = u8::wrapping_add(data[y * bytes_per_scanline + 1 + x], match filter_type {
-                        FilterType::None => 0,
-                        FilterType::Sub => a,
-                        FilterType::Up => b,
-                        FilterType::Average => (a + b) / 2,
-                        FilterType::Paeth => {
-                            let p = a + b - c;
-                            let pa = i16::abs(p - a);
-                            let pb = i16::abs(p - b);
-                            let pc = i16::abs(p - c);
-                            if pa <= pb && pa <= pc {a} else if pb <= pc {b} else {c}
-                        },
-                    } as u8);
-                }
-            }
-            for y in 0 .. height {
-                for x in 0 .. width {
-                    let i = y * bytes_per_scanline + 1 + x * bits_per_pixel / 8;
-                    let j = y * pitch + x * 4;
-                    let color = match color_mode {
-                        ColorMode::Grayscale1 => {
-                            let shift = (7 - x % 8) as u32;
-                            let color = (data[i].wrapping_shr(shift) & 0x01) * (255 / 1);
-                            (color, color, color, 255)
-                        },
-                        ColorMode::Grayscale2 => {
-                            let shift = ((3 - x % 4) * 2) as u32;
-                            let color = (data[i].wrapping_shr(shift) & 0x03) * (255 / 3);
-                            (color, color, color, 255)
-                        },
-                        ColorMode::Grayscale4 => {
-                            let shift = ((1 - x % 2) * 4) as u32;
-                            let color = (data[i].wrapping_shr(shift) & 0x0F) * (255 / 15);
-                            (color, color, color, 255)
-                        },
-                        ColorMode::Grayscale8 => (data[i], data[i], data[i], 255),
-                        ColorMode::Grayscale16 => {
-                            let color = color_16_to_8(data[i + 0], data[i + 1]);
-                            (color, color, color, 255)
-                        },
-                        ColorMode::RGB8 => (data[i + 0], data[i + 1], data[i + 2], 255),
-                        ColorMode::RGB16 => (
-                            color_16_to_8(data[i + 0], data[i + 1]),
-                            color_16_to_8(data[i + 2], data[i + 3]),
-                            color_16_to_8(data[i + 4], data[i + 5]),
-                            255,
-                        ),
-                        ColorMode::Palette1(palette) => {
-                            let index = (data[i].wrapping_shr((7 - x % 8) as u32) & 0x01) as usize;
-                            palette_color(palette, index)?
-                        },
-                        ColorMode::Palette2(palette) => {
-                            let index = (data[i].wrapping_shr(((3 - x % 4) * 2) as u32) & 0x03) as usize;
-                            palette_color(palette, index)?
-                        },
-                        ColorMode::Palette4(palette) => {
-                            let index = (data[i].wrapping_shr(((1 - x % 2) * 4) as u32) & 0x0F) as usize;
-                            palette_color(palette, index)?
-                        },
-                        ColorMode::Palette8(palette) => palette_color(palette, data[i] as usize)?,
-                        ColorMode::GrayscaleAlpha8 => (data[i + 0], data[i + 0], data[i + 0], data[i + 1]),
-                        ColorMode::GrayscaleAlpha16 => {
-                            let color = color_16_to_8(data[i + 0], data[i + 1]);
-                            let alpha = color_16_to_8(data[i + 2], data[i + 3]);
-                            (color, color, color, alpha)
-                        },
-                        ColorMode::RGBA8 => (data[i + 0], data[i + 1], data[i + 2], data[i + 3]),
-                        ColorMode::RGBA16 => (
-                            color_16_to_8(data[i + 0], data[i + 1]),
-                            color_16_to_8(data[i + 2], data[i + 3]),
-                            color_16_to_8(data[i + 4], data[i + 5]),
-                            color_16_to_8(data[i + 6], data[i + 7]),
-                        ),
-                        _ => std::todo!(),
-                    };
-                    pixels[j + 0] = color.2;
+        InterlaceMethod::NoInterlace => (width, height),
+        InterlaceMethod::Adam7 => adam7_pass_size(width, height, pass),
+    }
+}
+
+// (x offset, y offset, x spacing, y spacing) of the pixels of a pass in the full image
+fn pass_layout(interlace_method: InterlaceMethod, pass: usize) -> (usize, usize, usize, usize) {
+    match interlace_method {
+        InterlaceMethod::NoInterlace => (0, 0, 1, 1),
+        InterlaceMethod::Adam7 => ADAM7_PASSES[pass],
+    }
+}
+
+fn scanlines_length(width: usize, height: usize, color_mode: &ColorMode) -> usize {
+    if width == 0 || height == 0 {
+        return 0;
+    }
+    ((width * color_mode.bits_per_pixel()).div_ceil(8) + 1) * height
+}
+
+// Offsets in the decompressed data at which each pass ends
+pub fn pass_ends(width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod) -> Box<[usize]> {
+    let passes = match interlace_method {
+        InterlaceMethod::NoInterlace => 1,
+        InterlaceMethod::Adam7 => 7,
+    };
+    let mut end = 0;
+    (0 .. passes).map(|pass| {
+        let (pass_width, pass_height) = pass_size(width as usize, height as usize, interlace_method, pass);
+        end += scanlines_length(pass_width, pass_height, color_mode);
+        end
+    }).collect()
+}
+
+fn unfilter(data: &mut [u8], width: usize, height: usize, color_mode: &ColorMode) -> Result<()> {
+    let bytes_per_scanline = (width * color_mode.bits_per_pixel()).div_ceil(8) + 1;
+    let filter_bpp = color_mode.bits_per_pixel().div_ceil(8);
+    for y in 0 .. height {
+        let filter_type = FilterType::read(data[y * bytes_per_scanline])?;
+        for x in 0 .. bytes_per_scanline - 1 {
+            let a = if x >= filter_bpp { data[y * bytes_per_scanline + 1 + x - filter_bpp] } else { 0 };
+            let b = if y > 0 { data[(y - 1) * bytes_per_scanline + 1 + x] } else { 0 };
+            let c = if x >= filter_bpp && y > 0 { data[(y - 1) * bytes_per_scanline + 1 + x - filter_bpp] } else { 0 };
+            data[y * bytes_per_scanline + 1 + x] = u8::wrapping_add(data[y * bytes_per_scanline + 1 + x], filter_type.predict(a, b, c));
+        }
+    }
+    Ok(())
+}
+
+// Filters a row of packed samples, given the previous row of the same pass if there is one
+fn filter_row(filter_type: FilterType, row: &[u8], prev: Option<&[u8]>, filter_bpp: usize, out: &mut [u8]) {
+    for x in 0 .. row.len() {
+        let a = if x >= filter_bpp { row[x - filter_bpp] } else { 0 };
+        let b = prev.map_or(0, |prev| prev[x]);
+        let c = if x >= filter_bpp { prev.map_or(0, |prev| prev[x - filter_bpp]) } else { 0 };
+        out[x] = u8::wrapping_sub(row[x], filter_type.predict(a, b, c));
+    }
+}
+
+fn sum_of_absolute_values(filtered: &[u8]) -> u64 {
+    filtered.iter().map(|&byte| (byte as i8).unsigned_abs() as u64).sum()
+}
+
+fn entropy(filtered: &[u8]) -> f64 {
+    let mut counts = [0; 256];
+    for &byte in filtered {
+        counts[byte as usize] += 1;
+    }
+    let len = filtered.len() as f64;
+    counts.iter().filter(|&&count| count > 0).map(|&count| {
+        let count = count as f64;
+        -count * (count / len).log2()
+    }).sum()
+}
+
+// Returns the filter type for which the filtered row has the lowest cost, preferring earlier filter types on ties
+fn min_cost_filter<T, F>(row: &[u8], prev: Option<&[u8]>, filter_bpp: usize, mut cost: F) -> FilterType where T: PartialOrd, F: FnMut(FilterType, &[u8]) -> T {
+    let mut filtered = vec![0; row.len()];
+    let mut best = None;
+    for &filter_type in &FilterType::ALL {
+        filter_row(filter_type, row, prev, filter_bpp, &mut filtered);
+        let cost = cost(filter_type, &filtered);
+        match best {
+            Some((_, ref best_cost)) if *best_cost <= cost => (),
+            _ => best = Some((filter_type, cost)),
+        }
+    }
+    best.unwrap().0
+}
+
+// Chooses the filter type of a row according to the strategy, given the previous filtered scanline of the pass for brute force.
+// Returns the filter type and the filtered row.
+fn choose_filter(strategy: FilterStrategy, row: &[u8], prev: Option<&[u8]>, prev_filtered: &[u8], filter_bpp: usize, compression_level: u8) -> (FilterType, Vec<u8>) {
+    let filter_type = match strategy {
+        FilterStrategy::Fixed(filter_type) => filter_type,
+        FilterStrategy::MinimumSum => min_cost_filter(row, prev, filter_bpp, |_, filtered| sum_of_absolute_values(filtered)),
+        FilterStrategy::Entropy => min_cost_filter(row, prev, filter_bpp, |_, filtered| entropy(filtered)),
+        FilterStrategy::BruteForce => {
+            let mut trial = prev_filtered.to_vec();
+            min_cost_filter(row, prev, filter_bpp, |filter_type, filtered| {
+                trial.truncate(prev_filtered.len());
+                trial.push(filter_type as u8);
+                trial.extend_from_slice(filtered);
+                deflate::write_zlib(&trial, compression_level).len()
+            })
+        },
+    };
+    let mut filtered = vec![0; row.len()];
+    filter_row(filter_type, row, prev, filter_bpp, &mut filtered);
+    (filter_type, filtered)
+}
+
+// Reads the color of the pixel at position x of an unfiltered scanline (without the filter type byte)
+#[allow(clippy::identity_op)]
+fn pixel_color(scanline: &[u8], x: usize, color_mode: &ColorMode, conversion: &Conversion) -> Result<(u8, u8, u8, u8)> {
+    let i = x * color_mode.bits_per_pixel() / 8;
+    let transparency = conversion.transparency;
+    let sample = |j: usize| sample_16(scanline[i + j], scanline[i + j + 1]);
+    Ok(match color_mode {
+        ColorMode::Grayscale1 => {
+            let gray = (scanline[i].wrapping_shr((7 - x % 8) as u32) & 0x01) as u16;
+            let (r, g, b) = gray_to_8(gray, 1, conversion);
+            (r, g, b, gray_alpha(gray, transparency))
+        },
+        ColorMode::Grayscale2 => {
+            let gray = (scanline[i].wrapping_shr(((3 - x % 4) * 2) as u32) & 0x03) as u16;
+            let (r, g, b) = gray_to_8(gray, 2, conversion);
+            (r, g, b, gray_alpha(gray, transparency))
+        },
+        ColorMode::Grayscale4 => {
+            let gray = (scanline[i].wrapping_shr(((1 - x % 2) * 4) as u32) & 0x0F) as u16;
+            let (r, g, b) = gray_to_8(gray, 4, conversion);
+            (r, g, b, gray_alpha(gray, transparency))
+        },
+        ColorMode::Grayscale8 => {
+            let gray = scanline[i] as u16;
+            let (r, g, b) = gray_to_8(gray, 8, conversion);
+            (r, g, b, gray_alpha(gray, transparency))
+        },
+        ColorMode::Grayscale16 => {
+            let gray = sample(0);
+            let (r, g, b) = gray_to_8(gray, 16, conversion);
+            (r, g, b, gray_alpha(gray, transparency))
+        },
+        ColorMode::RGB8 => {
+            let rgb = (scanline[i + 0] as u16, scanline[i + 1] as u16, scanline[i + 2] as u16);
+            let (r, g, b) = rgb_to_8(rgb, 8, conversion);
+            (r, g, b, rgb_alpha(rgb, transparency))
+        },
+        ColorMode::RGB16 => {
+            let rgb = (sample(0), sample(2), sample(4));
+            let (r, g, b) = rgb_to_8(rgb, 16, conversion);
+            (r, g, b, rgb_alpha(rgb, transparency))
+        },
+        ColorMode::Palette1(palette) => {
+            let index = (scanline[i].wrapping_shr((7 - x % 8) as u32) & 0x01) as usize;
+            palette_color(palette, index, conversion)?
+        },
+        ColorMode::Palette2(palette) => {
+            let index = (scanline[i].wrapping_shr(((3 - x % 4) * 2) as u32) & 0x03) as usize;
+            palette_color(palette, index, conversion)?
+        },
+        ColorMode::Palette4(palette) => {
+            let index = (scanline[i].wrapping_shr(((1 - x % 2) * 4) as u32) & 0x0F) as usize;
+            palette_color(palette, index, conversion)?
+        },
+        ColorMode::Palette8(palette) => palette_color(palette, scanline[i] as usize, conversion)?,
+        ColorMode::GrayscaleAlpha8 => {
+            let (r, g, b) = gray_to_8(scanline[i + 0] as u16, 8, conversion);
+            (r, g, b, alpha_to_8(scanline[i + 1] as u16, 8, conversion))
+        },
+        ColorMode::GrayscaleAlpha16 => {
+            let (r, g, b) = gray_to_8(sample(0), 16, conversion);
+            (r, g, b, alpha_to_8(sample(2), 16, conversion))
+        },
+        ColorMode::RGBA8 => {
+            let (r, g, b) = rgb_to_8((scanline[i + 0] as u16, scanline[i + 1] as u16, scanline[i + 2] as u16), 8, conversion);
+            (r, g, b, alpha_to_8(scanline[i + 3] as u16, 8, conversion))
+        },
+        ColorMode::RGBA16 => {
+            let (r, g, b) = rgb_to_8((sample(0), sample(2), sample(4)), 16, conversion);
+            (r, g, b, alpha_to_8(sample(6), 16, conversion))
+        },
+    })
+}
+
+// Writes the pixels of an unfiltered reduced image to every (dx, dy)-th pixel starting at (x0, y0),
+// filling a block of (bw, bh) pixels with each of them
+#[allow(clippy::identity_op, clippy::too_many_arguments)]
+fn write_pixels(data: &[u8], pixels: &mut [u8], pitch: usize, width: usize, height: usize, full_width: usize, full_height: usize, color_mode: &ColorMode, conversion: &Conversion, (x0, y0, dx, dy): (usize, usize, usize, usize), (bw, bh): (usize, usize)) -> Result<()> {
+    let bytes_per_scanline = (width * color_mode.bits_per_pixel()).div_ceil(8) + 1;
+    for y in 0 .. height {
+        let scanline = &data[y * bytes_per_scanline + 1 .. (y + 1) * bytes_per_scanline];
+        for x in 0 .. width {
+            let color = pixel_color(scanline, x, color_mode, conversion)?;
+            for by in y0 + y * dy .. usize::min(y0 + y * dy + bh, full_height) {
+                for bx in x0 + x * dx .. usize::min(x0 + x * dx + bw, full_width) {
+                    let j = by * pitch + bx * 4;
+                    pixels[j + 0] = color.0;
                     pixels[j + 1] = color.1;
-                    pixels[j + 2] = color.0;
+                    pixels[j + 2] = color.2;
                     pixels[j + 3] = color.3;
                 }
             }
-        },
-        InterlaceMethod::Adam7 => std::todo!(),
+        }
     }
     Ok(())
 }
+
+// Unfilters a single pass and writes it to the pixel buffer.
+// The data has to contain the decompressed data at least up to the end of the pass.
+// It is not modified, so that the part after the pass can still be referenced by the decompressor.
+// Pixels of Adam7 passes other than the last one are replicated over the blocks that later passes will fill in.
+#[allow(clippy::too_many_arguments)]
+pub fn unfilter_uninterlace_pass(data: &[u8], pixels: &mut [u8], pitch: usize, width: u32, height: u32, color_mode: &ColorMode, conversion: &Conversion, interlace_method: InterlaceMethod, pass: usize) -> Result<()> {
+    let width = width as usize;
+    let height = height as usize;
+    let (pass_width, pass_height) = pass_size(width, height, interlace_method, pass);
+    if pass_width == 0 || pass_height == 0 {
+        return Ok(());
+    }
+    let start = if pass == 0 { 0 } else { pass_ends(width as u32, height as u32, color_mode, interlace_method)[pass - 1] };
+    let mut pass_data = data[start .. start + scanlines_length(pass_width, pass_height, color_mode)].to_vec();
+    unfilter(&mut pass_data, pass_width, pass_height, color_mode)?;
+    let (layout, block) = match interlace_method {
+        InterlaceMethod::NoInterlace => ((0, 0, 1, 1), (1, 1)),
+        InterlaceMethod::Adam7 => (ADAM7_PASSES[pass], ADAM7_BLOCKS[pass]),
+    };
+    write_pixels(&pass_data, pixels, pitch, pass_width, pass_height, width, height, color_mode, conversion, layout, block)
+}
+
+// Copies the pixel at position src_x of a row of packed samples to position dst_x of another
+fn copy_pixel(src: &[u8], src_x: usize, dst: &mut [u8], dst_x: usize, bits_per_pixel: usize) {
+    if bits_per_pixel >= 8 {
+        let bytes = bits_per_pixel / 8;
+        dst[dst_x * bytes .. (dst_x + 1) * bytes].copy_from_slice(&src[src_x * bytes .. (src_x + 1) * bytes]);
+    } else {
+        let mask = ((1 << bits_per_pixel) - 1) as u8;
+        let src_shift = 8 - bits_per_pixel - src_x * bits_per_pixel % 8;
+        let dst_shift = 8 - bits_per_pixel - dst_x * bits_per_pixel % 8;
+        let value = (src[src_x * bits_per_pixel / 8] >> src_shift) & mask;
+        let byte = &mut dst[dst_x * bits_per_pixel / 8];
+        *byte = (*byte & !(mask << dst_shift)) | (value << dst_shift);
+    }
+}
+
+// Unfilters a single pass like unfilter_uninterlace_pass, but copies the packed samples into rows of the buffer unconverted.
+// Pixels of Adam7 passes are only written to their own positions.
+#[allow(clippy::too_many_arguments)]
+pub fn unfilter_uninterlace_pass_raw(data: &[u8], samples: &mut [u8], pitch: usize, width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod, pass: usize) -> Result<()> {
+    let width = width as usize;
+    let height = height as usize;
+    let (pass_width, pass_height) = pass_size(width, height, interlace_method, pass);
+    if pass_width == 0 || pass_height == 0 {
+        return Ok(());
+    }
+    let start = if pass == 0 { 0 } else { pass_ends(width as u32, height as u32, color_mode, interlace_method)[pass - 1] };
+    let mut pass_data = data[start .. start + scanlines_length(pass_width, pass_height, color_mode)].to_vec();
+    unfilter(&mut pass_data, pass_width, pass_height, color_mode)?;
+    let bytes_per_scanline = (pass_width * color_mode.bits_per_pixel()).div_ceil(8) + 1;
+    let (x0, y0, dx, dy) = pass_layout(interlace_method, pass);
+    for y in 0 .. pass_height {
+        let scanline = &pass_data[y * bytes_per_scanline + 1 .. (y + 1) * bytes_per_scanline];
+        let row = &mut samples[(y0 + y * dy) * pitch ..];
+        if dx == 1 {
+            row[.. bytes_per_scanline - 1].copy_from_slice(scanline);
+        } else {
+            for x in 0 .. pass_width {
+                copy_pixel(scanline, x, row, x0 + x * dx, color_mode.bits_per_pixel());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Splits rows of packed samples into the scanlines of each pass and filters them, choosing filter types with the strategy.
+// The compression level is used for trial compression by the brute force strategy.
+// Returns the scanlines and the filter type of each of them.
+#[allow(clippy::too_many_arguments)]
+pub fn filter_scanlines(samples: &[u8], pitch: usize, width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod, strategy: FilterStrategy, compression_level: u8) -> (Vec<u8>, Vec<FilterType>) {
+    let width = width as usize;
+    let height = height as usize;
+    let ends = pass_ends(width as u32, height as u32, color_mode, interlace_method);
+    let filter_bpp = color_mode.bits_per_pixel().div_ceil(8);
+    let mut data = Vec::with_capacity(ends[ends.len() - 1]);
+    let mut filter_types = Vec::new();
+    for pass in 0 .. ends.len() {
+        let (pass_width, pass_height) = pass_size(width, height, interlace_method, pass);
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+        let (x0, y0, dx, dy) = pass_layout(interlace_method, pass);
+        let bytes_per_row = (pass_width * color_mode.bits_per_pixel()).div_ceil(8);
+        let mut prev_row: Option<Vec<u8>> = None;
+        let mut prev_filtered = Vec::new();
+        for y in 0 .. pass_height {
+            let samples_row = &samples[(y0 + y * dy) * pitch ..];
+            let row = if dx == 1 {
+                samples_row[.. bytes_per_row].to_vec()
+            } else {
+                let mut row = vec![0; bytes_per_row];
+                for x in 0 .. pass_width {
+                    copy_pixel(samples_row, x0 + x * dx, &mut row, x, color_mode.bits_per_pixel());
+                }
+                row
+            };
+            let (filter_type, filtered) = choose_filter(strategy, &row, prev_row.as_deref(), &prev_filtered, filter_bpp, compression_level);
+            data.push(filter_type as u8);
+            data.extend_from_slice(&filtered);
+            filter_types.push(filter_type);
+            prev_filtered.clear();
+            prev_filtered.push(filter_type as u8);
+            prev_filtered.extend_from_slice(&filtered);
+            prev_row = Some(row);
+        }
+    }
+    (data, filter_types)
+}
+
+// Palette with the given number of distinct entries, for tests
+#[cfg(test)]
+pub fn test_palette(len: usize) -> Palette {
+    (0 .. len).map(|i| (i as u8, (i * 3) as u8, (255 - i) as u8)).collect()
+}
+
+// Every color mode, with palettes of as many entries as their bit depth allows, for tests
+#[cfg(test)]
+pub fn test_color_modes() -> Vec<ColorMode> {
+    use ColorMode::*;
+    vec![
+        Grayscale1, Grayscale2, Grayscale4, Grayscale8, Grayscale16, RGB8, RGB16,
+        Palette1(test_palette(2)), Palette2(test_palette(4)), Palette4(test_palette(16)), Palette8(test_palette(256)),
+        GrayscaleAlpha8, GrayscaleAlpha16, RGBA8, RGBA16,
+    ]
+}
+
+// Pseudo-random rows of packed samples for tests, with a few extra bytes at the end of each row.
+// The padding bits of the last byte of each row are cleared, since decoding does not write them.
+// Returns the samples and the pitch.
+#[cfg(test)]
+pub fn test_samples(width: u32, height: u32, color_mode: &ColorMode) -> (Vec<u8>, usize) {
+    let bytes_per_row = color_mode.bytes_per_row(width);
+    let pitch = bytes_per_row + 3;
+    let mut state: u32 = width * 31 + height;
+    let mut samples: Vec<u8> = (0 .. pitch * height as usize).map(|_| {
+        state = state.wrapping_mul(1103515245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect();
+    let padding_bits = bytes_per_row * 8 - width as usize * color_mode.bits_per_pixel();
+    for row in samples.chunks_mut(pitch) {
+        row[bytes_per_row - 1] &= !((1u16 << padding_bits) - 1) as u8;
+    }
+    (samples, pitch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::chunk_bytes;
+    use crate::decoder::PNG_SIG;
+    use crate::file::SliceReader;
+    use crate::zlib;
+    use crate::Decoder;
+    use crate::Encoder;
+    use std::io::Cursor;
+
+    const STRATEGIES: [FilterStrategy; 8] = [
+        FilterStrategy::Fixed(FilterType::None),
+        FilterStrategy::Fixed(FilterType::Sub),
+        FilterStrategy::Fixed(FilterType::Up),
+        FilterStrategy::Fixed(FilterType::Average),
+        FilterStrategy::Fixed(FilterType::Paeth),
+        FilterStrategy::MinimumSum,
+        FilterStrategy::Entropy,
+        FilterStrategy::BruteForce,
+    ];
+
+    // Filter type bytes at the start of each scanline of the decompressed data, pass by pass
+    fn stored_filter_types(data: &[u8], width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod) -> Vec<u8> {
+        let ends = pass_ends(width, height, color_mode, interlace_method);
+        let mut filter_types = Vec::new();
+        let mut start = 0;
+        for pass in 0 .. ends.len() {
+            let (pass_width, _) = pass_size(width as usize, height as usize, interlace_method, pass);
+            let bytes_per_scanline = (pass_width * color_mode.bits_per_pixel()).div_ceil(8) + 1;
+            filter_types.extend(data[start .. ends[pass]].iter().step_by(bytes_per_scanline));
+            start = ends[pass];
+        }
+        filter_types
+    }
+
+    fn filter_round_trip(width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod, strategy: FilterStrategy) {
+        let (samples, pitch) = test_samples(width, height, color_mode);
+        let (data, filter_types) = filter_scanlines(&samples, pitch, width, height, color_mode, interlace_method, strategy, 6);
+        let ends = pass_ends(width, height, color_mode, interlace_method);
+        assert_eq!(data.len(), ends[ends.len() - 1]);
+        let stored: Vec<u8> = filter_types.iter().map(|&filter_type| filter_type as u8).collect();
+        assert_eq!(stored_filter_types(&data, width, height, color_mode, interlace_method), stored);
+        if let FilterStrategy::Fixed(fixed) = strategy {
+            assert!(filter_types.iter().all(|&filter_type| filter_type == fixed));
+        }
+        let mut unfiltered = vec![0; samples.len()];
+        for pass in 0 .. ends.len() {
+            unfilter_uninterlace_pass_raw(&data, &mut unfiltered, pitch, width, height, color_mode, interlace_method, pass).unwrap();
+        }
+        let bytes_per_row = color_mode.bytes_per_row(width);
+        for (row, unfiltered_row) in samples.chunks(pitch).zip(unfiltered.chunks(pitch)) {
+            assert_eq!(row[.. bytes_per_row], unfiltered_row[.. bytes_per_row], "{:?}, {}x{}", strategy, width, height);
+        }
+    }
+
+    #[test]
+    fn unfiltering_reverses_every_strategy() {
+        for color_mode in &test_color_modes() {
+            for &strategy in &STRATEGIES {
+                for &(width, height) in &[(1, 1), (3, 5), (9, 4), (20, 9)] {
+                    filter_round_trip(width, height, color_mode, InterlaceMethod::NoInterlace, strategy);
+                    filter_round_trip(width, height, color_mode, InterlaceMethod::Adam7, strategy);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn strategies_choose_different_filters() {
+        // Rows of a horizontal gradient are cheapest with Sub, and repeated rows with Up
+        let color_mode = ColorMode::Grayscale8;
+        let samples: Vec<u8> = (0 .. 16).flat_map(|_| (0 .. 64).map(|x| (x * 3) as u8)).collect();
+        for &strategy in &STRATEGIES[5 ..] {
+            let (_, filter_types) = filter_scanlines(&samples, 64, 64, 16, &color_mode, InterlaceMethod::NoInterlace, strategy, 6);
+            assert!(filter_types.iter().any(|&filter_type| filter_type != FilterType::None), "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn encoder_reports_filter_types_pass_by_pass() {
+        let (width, height) = (13, 11);
+        let color_mode = ColorMode::RGB8;
+        let (samples, pitch) = test_samples(width, height, &color_mode);
+        let mut encoder = Encoder::new(width, height, color_mode.clone()).unwrap();
+        encoder.set_interlace_method(InterlaceMethod::Adam7);
+        let png = encoder.encode(Vec::new(), &samples, pitch).unwrap();
+        // Collect the data of the IDAT chunks following the signature and IHDR chunk
+        let mut compressed = Vec::new();
+        let mut i = 8 + 25;
+        while i < png.len() {
+            let length = u32::from_be_bytes([png[i], png[i + 1], png[i + 2], png[i + 3]]) as usize;
+            if &png[i + 4 .. i + 8] == b"IDAT" {
+                compressed.extend_from_slice(&png[i + 8 .. i + 8 + length]);
+            }
+            i += length + 12;
+        }
+        let mut data = Vec::new();
+        zlib::read_zlib(SliceReader::new(&compressed), &mut data, usize::MAX, &[], |_| Ok(())).unwrap();
+        let scanlines: usize = (0 .. 7).map(|pass| match adam7_pass_size(width as usize, height as usize, pass) {
+            (0, _) => 0,
+            (_, pass_height) => pass_height,
+        }).sum();
+        let reported: Vec<u8> = encoder.filter_types().iter().map(|&filter_type| filter_type as u8).collect();
+        assert_eq!(reported.len(), scanlines);
+        assert_eq!(stored_filter_types(&data, width, height, &color_mode, InterlaceMethod::Adam7), reported);
+    }
+
+    // Adam7 pass of each pixel of an 8x8 block, numbered from 1 as in the PNG specification
+    const ADAM7_PATTERN: [[usize; 8]; 8] = [
+        [1, 6, 4, 6, 2, 6, 4, 6],
+        [7; 8],
+        [5, 6, 5, 6, 5, 6, 5, 6],
+        [7; 8],
+        [3, 6, 4, 6, 3, 6, 4, 6],
+        [7; 8],
+        [5, 6, 5, 6, 5, 6, 5, 6],
+        [7; 8],
+    ];
+
+    // Builds an Adam7 interlaced file with unfiltered scanlines from the pattern, independently of the encoder
+    fn interlaced_png(samples: &[u8], pitch: usize, width: u32, height: u32, color_mode: &ColorMode) -> Vec<u8> {
+        let bits_per_pixel = color_mode.bits_per_pixel();
+        let mut scanlines = Vec::new();
+        for pass in 1 ..= 7 {
+            for y in 0 .. height as usize {
+                let xs: Vec<usize> = (0 .. width as usize).filter(|&x| ADAM7_PATTERN[y % 8][x % 8] == pass).collect();
+                if xs.is_empty() {
+                    continue;
+                }
+                let mut row = vec![0; (xs.len() * bits_per_pixel).div_ceil(8)];
+                for (i, &x) in xs.iter().enumerate() {
+                    copy_pixel(&samples[y * pitch ..], x, &mut row, i, bits_per_pixel);
+                }
+                scanlines.push(FilterType::None as u8);
+                scanlines.extend(row);
+            }
+        }
+        let mut ihdr = width.to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[color_mode.bit_depth(), color_mode.color_type(), 0, 0, 1]);
+        let mut png = PNG_SIG.to_vec();
+        png.extend(chunk_bytes(b"IHDR", &ihdr));
+        if let Some(palette) = color_mode.palette() {
+            png.extend(chunk_bytes(b"PLTE", &palette.iter().flat_map(|&(r, g, b)| vec![r, g, b]).collect::<Vec<u8>>()));
+        }
+        png.extend(chunk_bytes(b"IDAT", &deflate::write_zlib(&scanlines, 6)));
+        png.extend(chunk_bytes(b"IEND", &[]));
+        png
+    }
+
+    fn decode_pixels(png: Vec<u8>, width: u32, height: u32) -> Vec<u8> {
+        let mut pixels = vec![0; width as usize * height as usize * 4];
+        Decoder::new(Cursor::new(png)).unwrap().decode(&mut pixels, width as usize * 4).unwrap();
+        pixels
+    }
+
+    #[test]
+    fn adam7_matches_non_interlaced() {
+        for color_mode in &test_color_modes() {
+            for width in 1 ..= 9 {
+                for height in 1 ..= 9 {
+                    let (samples, pitch) = test_samples(width, height, color_mode);
+                    let interlaced = decode_pixels(interlaced_png(&samples, pitch, width, height, color_mode), width, height);
+                    let png = Encoder::new(width, height, color_mode.clone()).unwrap().encode(Vec::new(), &samples, pitch).unwrap();
+                    assert!(interlaced == decode_pixels(png, width, height),
+                        "color type {}, bit depth {}, {}x{}", color_mode.color_type(), color_mode.bit_depth(), width, height);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn adam7_pass_sizes() {
+        // Passes of images smaller than 5x5 are empty
+        let sizes: Vec<(usize, usize)> = (0 .. 7).map(|pass| adam7_pass_size(1, 1, pass)).collect();
+        assert_eq!(sizes, [(1, 1), (0, 1), (1, 0), (0, 1), (1, 0), (0, 1), (1, 0)]);
+        for width in 1 ..= 9 {
+            for height in 1 ..= 9 {
+                let pixels: usize = (0 .. 7).map(|pass| {
+                    let (pass_width, pass_height) = adam7_pass_size(width, height, pass);
+                    pass_width * pass_height
+                }).sum();
+                assert_eq!(pixels, width * height);
+            }
+        }
+    }
+}
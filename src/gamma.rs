@@ -0,0 +1,44 @@
+use crate::chunk::ChunkReader;
+use crate::file::ByteReader;
+use crate::Result;
+use std::io::Read;
+
+// Returns the gamma stored in a gAMA chunk times 100000, or None if the chunk is invalid
+pub fn load_gama<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<u32>> where R: Read {
+    if length != 4 {
+        warn!("gAMA chunk has incorrect length");
+        return Ok(None);
+    }
+    let gamma = chunk.read_u32()?;
+    info!("Gamma: {}", gamma as f64 / 100000.0);
+    if gamma == 0 {
+        warn!("Gamma is zero");
+        return Ok(None);
+    }
+    Ok(Some(gamma))
+}
+
+// Lookup tables correcting samples encoded with the file gamma for a display with the given gamma,
+// from 8-bit and 16-bit samples to 8-bit output
+pub struct GammaTables {
+    table_8: Box<[u8]>,
+    table_16: Box<[u8]>,
+}
+
+impl GammaTables {
+    pub fn new(file_gamma: u32, display_gamma: f64) -> GammaTables {
+        let exponent = 1.0 / (file_gamma as f64 / 100000.0 * display_gamma);
+        let correct = |sample: f64| (sample.powf(exponent) * 255.0).round() as u8;
+        let table_8 = (0 ..= 0xFF).map(|i| correct(i as f64 / 255.0)).collect();
+        let table_16 = (0 ..= 0xFFFF).map(|i| correct(i as f64 / 65535.0)).collect();
+        GammaTables { table_8, table_16 }
+    }
+
+    pub fn correct_8(&self, sample: u8) -> u8 {
+        self.table_8[sample as usize]
+    }
+
+    pub fn correct_16(&self, sample: u16) -> u8 {
+        self.table_16[sample as usize]
+    }
+}
@@ -0,0 +1,178 @@
+use crate::chunk::ChunkReader;
+use crate::color;
+use crate::color::Chromaticities;
+use crate::color::ColorTransform;
+use crate::decoder::ImageInfo;
+use crate::file::ByteReader;
+use crate::ihdr::ColorMode;
+use crate::Result;
+use std::io::Read;
+
+// Code points from ITU-T H.273 used by cICP chunks
+const PRIMARIES_BT709: u8 = 1;
+const PRIMARIES_BT2020: u8 = 9;
+const PRIMARIES_DISPLAY_P3: u8 = 12;
+const TRANSFER_PQ: u8 = 16;
+const TRANSFER_HLG: u8 = 18;
+
+// Luminance in cd/m² which SDR white is mapped from
+const REFERENCE_WHITE: f64 = 203.0;
+// Peak luminance assumed when the file does not specify one, which is also the nominal peak of HLG
+const DEFAULT_PEAK_LUMINANCE: f64 = 1000.0;
+
+// Color space of the image from a cICP chunk
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CodingIndependentCodePoints {
+    pub color_primaries: u8,
+    pub transfer_function: u8,
+    pub matrix_coefficients: u8,
+    pub full_range: bool,
+}
+
+// Color volume of the display the image was mastered on from an mDCv chunk.
+// Chromaticities are in units of 0.00002 and luminances in units of 0.0001 cd/m².
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MasteringDisplay {
+    pub primaries: [(u16, u16); 3],
+    pub white_point: (u16, u16),
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+// Content light levels from a cLLi chunk, in units of 0.0001 cd/m²
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ContentLightLevel {
+    pub max_content: u32,
+    pub max_frame_average: u32,
+}
+
+// Returns None if the chunk is invalid, in which case it should be ignored
+pub fn load_cicp<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<CodingIndependentCodePoints>> where R: Read {
+    if length != 4 {
+        warn!("cICP chunk has incorrect length");
+        return Ok(None);
+    }
+    let color_primaries = chunk.read_u8()?;
+    let transfer_function = chunk.read_u8()?;
+    let matrix_coefficients = chunk.read_u8()?;
+    let full_range = chunk.read_u8()?;
+    info!("Color primaries: {}", color_primaries);
+    info!("Transfer function: {}", transfer_function);
+    info!("Matrix coefficients: {}", matrix_coefficients);
+    info!("Full range: {}", full_range);
+    if matrix_coefficients != 0 {
+        warn!("cICP matrix coefficients are not RGB");
+        return Ok(None);
+    }
+    let full_range = match full_range {
+        0 => false,
+        1 => true,
+        _ => {
+            warn!("Invalid cICP full range flag");
+            return Ok(None);
+        },
+    };
+    Ok(Some(CodingIndependentCodePoints { color_primaries, transfer_function, matrix_coefficients, full_range }))
+}
+
+pub fn load_mdcv<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<MasteringDisplay>> where R: Read {
+    if length != 24 {
+        warn!("mDCv chunk has incorrect length");
+        return Ok(None);
+    }
+    let mut primaries = [(0, 0); 3];
+    for primary in primaries.iter_mut() {
+        *primary = (chunk.read_u16()?, chunk.read_u16()?);
+    }
+    let white_point = (chunk.read_u16()?, chunk.read_u16()?);
+    let max_luminance = chunk.read_u32()?;
+    let min_luminance = chunk.read_u32()?;
+    info!("Mastering display primaries: {:?}", primaries);
+    info!("Mastering display white point: {:?}", white_point);
+    info!("Mastering display luminance: {} - {} cd/m²", min_luminance as f64 / 10000.0, max_luminance as f64 / 10000.0);
+    if min_luminance >= max_luminance {
+        warn!("Mastering display minimum luminance is not below maximum luminance");
+    }
+    Ok(Some(MasteringDisplay { primaries, white_point, max_luminance, min_luminance }))
+}
+
+pub fn load_clli<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<ContentLightLevel>> where R: Read {
+    if length != 8 {
+        warn!("cLLi chunk has incorrect length");
+        return Ok(None);
+    }
+    let max_content = chunk.read_u32()?;
+    let max_frame_average = chunk.read_u32()?;
+    info!("Maximum content light level: {} cd/m²", max_content as f64 / 10000.0);
+    info!("Maximum frame-average light level: {} cd/m²", max_frame_average as f64 / 10000.0);
+    Ok(Some(ContentLightLevel { max_content, max_frame_average }))
+}
+
+// PQ (SMPTE ST 2084) electro-optical transfer function, giving luminance in cd/m²
+fn pq_to_luminance(x: f64) -> f64 {
+    const M1: f64 = 2610.0 / 16384.0;
+    const M2: f64 = 2523.0 / 4096.0 * 128.0;
+    const C1: f64 = 3424.0 / 4096.0;
+    const C2: f64 = 2413.0 / 4096.0 * 32.0;
+    const C3: f64 = 2392.0 / 4096.0 * 32.0;
+    let p = x.powf(1.0 / M2);
+    ((p - C1).max(0.0) / (C2 - C3 * p)).powf(1.0 / M1) * 10000.0
+}
+
+// HLG (ARIB STD-B67) inverse OETF followed by the OOTF for a display with the nominal peak, giving luminance in cd/m²
+fn hlg_to_luminance(x: f64) -> f64 {
+    const A: f64 = 0.17883277;
+    const B: f64 = 0.28466892;
+    const C: f64 = 0.55991073;
+    let scene = if x <= 0.5 { x * x / 3.0 } else { (((x - C) / A).exp() + B) / 12.0 };
+    scene.powf(1.2) * DEFAULT_PEAK_LUMINANCE
+}
+
+// Extended Reinhard curve mapping luminance relative to reference white to the SDR range, reaching 1 at the peak
+fn tone_map(luminance: f64, peak: f64) -> f64 {
+    let l = luminance / REFERENCE_WHITE;
+    let p = f64::max(peak / REFERENCE_WHITE, 1.0);
+    l * (1.0 + l / (p * p)) / (1.0 + l)
+}
+
+fn primaries_chromaticities(color_primaries: u8) -> Option<Chromaticities> {
+    let white = (31270, 32900);
+    match color_primaries {
+        PRIMARIES_BT709 => Some(Chromaticities { white, red: (64000, 33000), green: (30000, 60000), blue: (15000, 6000) }),
+        PRIMARIES_BT2020 => Some(Chromaticities { white, red: (70800, 29200), green: (17000, 79700), blue: (13100, 4600) }),
+        PRIMARIES_DISPLAY_P3 => Some(Chromaticities { white, red: (68000, 32000), green: (26500, 69000), blue: (15000, 6000) }),
+        _ => None,
+    }
+}
+
+// Builds the transform tone mapping a PQ or HLG image to SDR sRGB.
+// The peak luminance is taken from cLLi or mDCv if present.
+// Returns None if the image does not use an HDR transfer function.
+pub fn tone_mapping_transform(info: &ImageInfo) -> Option<ColorTransform> {
+    let cicp = info.cicp?;
+    let to_luminance = match cicp.transfer_function {
+        TRANSFER_PQ => pq_to_luminance,
+        TRANSFER_HLG => hlg_to_luminance,
+        _ => return None,
+    };
+    if !cicp.full_range {
+        warn!("Tone mapping narrow range images is not supported");
+        return None;
+    }
+    let matrix = match primaries_chromaticities(cicp.color_primaries).and_then(|chromaticities| color::chromaticities_matrix(&chromaticities)) {
+        Some(matrix) => matrix,
+        None => {
+            warn!("Unsupported cICP color primaries");
+            return None;
+        },
+    };
+    let peak = match (info.content_light_level, info.mastering_display) {
+        (Some(light_level), _) if light_level.max_content != 0 => light_level.max_content as f64 / 10000.0,
+        (_, Some(display)) if display.max_luminance != 0 => display.max_luminance as f64 / 10000.0,
+        _ => DEFAULT_PEAK_LUMINANCE,
+    };
+    let peak = if cicp.transfer_function == TRANSFER_HLG { DEFAULT_PEAK_LUMINANCE } else { peak };
+    info!("Tone mapping from peak luminance: {} cd/m²", peak);
+    let sixteen_bit = matches!(info.color_mode, ColorMode::Grayscale16 | ColorMode::GrayscaleAlpha16 | ColorMode::RGB16 | ColorMode::RGBA16);
+    Some(ColorTransform::new(|_, x| tone_map(to_luminance(x), peak), matrix, sixteen_bit))
+}
@@ -0,0 +1,161 @@
+// Minimal parser for ICC profiles, supporting only matrix/TRC RGB profiles and gray TRC profiles
+
+use crate::color::Matrix;
+
+pub enum Curve {
+    Gamma(f64),
+    Table(Box<[u16]>),
+    // Function type and parameters of a parametric curve
+    Parametric(u16, [f64; 7]),
+}
+
+impl Curve {
+    // Maps a sample in the range [0, 1] to linear light
+    pub fn eval(&self, x: f64) -> f64 {
+        let y = match self {
+            Curve::Gamma(gamma) => x.powf(*gamma),
+            Curve::Table(table) => {
+                let pos = x * (table.len() - 1) as f64;
+                let i = usize::min(pos.floor() as usize, table.len() - 1);
+                let j = usize::min(i + 1, table.len() - 1);
+                let t = pos - i as f64;
+                (table[i] as f64 * (1.0 - t) + table[j] as f64 * t) / 65535.0
+            },
+            Curve::Parametric(function_type, p) => {
+                let [g, a, b, c, d, e, f] = *p;
+                match function_type {
+                    0 => x.powf(g),
+                    1 => if x >= -b / a { (a * x + b).powf(g) } else { 0.0 },
+                    2 => if x >= -b / a { (a * x + b).powf(g) + c } else { c },
+                    3 => if x >= d { (a * x + b).powf(g) } else { c * x },
+                    _ => if x >= d { (a * x + b).powf(g) + e } else { c * x + f },
+                }
+            },
+        };
+        y.clamp(0.0, 1.0)
+    }
+}
+
+pub enum IccColorSpace {
+    // Matrix converting linear RGB to the D50 XYZ profile connection space, and the curve of each channel
+    Rgb(Matrix, [Curve; 3]),
+    Gray(Curve),
+}
+
+fn u16_at(profile: &[u8], offset: usize) -> Option<u16> {
+    let bytes = profile.get(offset .. offset + 2)?;
+    Some(((bytes[0] as u16) << 8) | bytes[1] as u16)
+}
+
+fn u32_at(profile: &[u8], offset: usize) -> Option<u32> {
+    let bytes = profile.get(offset .. offset + 4)?;
+    Some(((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) | bytes[3] as u32)
+}
+
+fn s15_fixed_16_at(profile: &[u8], offset: usize) -> Option<f64> {
+    Some(u32_at(profile, offset)? as i32 as f64 / 65536.0)
+}
+
+// Returns the data of the tag with the given signature
+fn find_tag<'a>(profile: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+    let count = u32_at(profile, 128)? as usize;
+    for i in 0 .. count {
+        let entry = 132 + i * 12;
+        if profile.get(entry .. entry + 4)? == signature {
+            let offset = u32_at(profile, entry + 4)? as usize;
+            let size = u32_at(profile, entry + 8)? as usize;
+            return profile.get(offset .. offset.checked_add(size)?);
+        }
+    }
+    None
+}
+
+fn read_xyz(profile: &[u8], signature: &[u8; 4]) -> Option<[f64; 3]> {
+    let tag = find_tag(profile, signature)?;
+    if tag.get(0 .. 4)? != b"XYZ " {
+        return None;
+    }
+    Some([s15_fixed_16_at(tag, 8)?, s15_fixed_16_at(tag, 12)?, s15_fixed_16_at(tag, 16)?])
+}
+
+fn read_curve(profile: &[u8], signature: &[u8; 4]) -> Option<Curve> {
+    let tag = find_tag(profile, signature)?;
+    match tag.get(0 .. 4)? {
+        b"curv" => {
+            let count = u32_at(tag, 8)? as usize;
+            match count {
+                0 => Some(Curve::Gamma(1.0)),
+                1 => Some(Curve::Gamma(u16_at(tag, 12)? as f64 / 256.0)),
+                _ => (0 .. count).map(|i| u16_at(tag, 12 + i * 2)).collect::<Option<_>>().map(Curve::Table),
+            }
+        },
+        b"para" => {
+            let function_type = u16_at(tag, 8)?;
+            let params_num = match function_type {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return None,
+            };
+            let mut params = [0.0; 7];
+            for (i, param) in params.iter_mut().enumerate().take(params_num) {
+                *param = s15_fixed_16_at(tag, 12 + i * 4)?;
+            }
+            Some(Curve::Parametric(function_type, params))
+        },
+        _ => None,
+    }
+}
+
+pub fn parse_profile(profile: &[u8]) -> Option<IccColorSpace> {
+    if profile.len() < 132 {
+        warn!("ICC profile is too short");
+        return None;
+    }
+    let color_space = &profile[16 .. 20];
+    let connection_space = &profile[20 .. 24];
+    info!("ICC profile color space: {}", String::from_utf8_lossy(color_space));
+    if connection_space != b"XYZ " {
+        warn!("Unsupported ICC profile connection space");
+        return None;
+    }
+    match color_space {
+        b"RGB " => {
+            let (red, green, blue) = match (read_xyz(profile, b"rXYZ"), read_xyz(profile, b"gXYZ"), read_xyz(profile, b"bXYZ")) {
+                (Some(red), Some(green), Some(blue)) => (red, green, blue),
+                _ => {
+                    warn!("Unsupported ICC profile without colorant tags");
+                    return None;
+                },
+            };
+            let curves = match (read_curve(profile, b"rTRC"), read_curve(profile, b"gTRC"), read_curve(profile, b"bTRC")) {
+                (Some(red), Some(green), Some(blue)) => [red, green, blue],
+                _ => {
+                    warn!("Unsupported ICC profile without tone reproduction curves");
+                    return None;
+                },
+            };
+            let matrix = [
+                [red[0], green[0], blue[0]],
+                [red[1], green[1], blue[1]],
+                [red[2], green[2], blue[2]],
+            ];
+            Some(IccColorSpace::Rgb(matrix, curves))
+        },
+        b"GRAY" => {
+            match read_curve(profile, b"kTRC") {
+                Some(curve) => Some(IccColorSpace::Gray(curve)),
+                None => {
+                    warn!("Unsupported ICC profile without gray tone reproduction curve");
+                    None
+                },
+            }
+        },
+        _ => {
+            warn!("Unsupported ICC profile color space");
+            None
+        },
+    }
+}
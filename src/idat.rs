@@ -2,58 +2,145 @@ use crate::chunk::ChunkReader;
 use crate::file::ByteReader;
 use crate::Error;
 use crate::Result;
+use std::io::Read;
 
-pub struct IdatReader {
-    chunk: ChunkReader,
+const USED_AFTER_ERROR: &str = "Image data reader used after an error";
+
+// Reads image data continuing across consecutive IDAT chunks,
+// or frame data across consecutive fdAT chunks with the sequence number at the start of each chunk stripped
+// The current chunk is taken out while moving to the next one, and is missing if that failed.
+pub struct IdatReader<R> where R: Read {
+    chunk: Option<ChunkReader<R>>,
+    chunk_type: [u8; 4],
+    next_sequence_number: Option<u32>,
+}
+
+impl<R> IdatReader<R> where R: Read {
+    pub fn new(chunk: ChunkReader<R>) -> Result<IdatReader<R>> {
+        Ok(IdatReader { chunk: Some(chunk), chunk_type: *b"IDAT", next_sequence_number: None })
+    }
+
+    // Creates a reader starting with the given fdAT chunk, expecting sequence numbers to continue from the given one
+    pub fn new_fdat(chunk: ChunkReader<R>, next_sequence_number: u32) -> Result<IdatReader<R>> {
+        let mut reader = IdatReader { chunk: Some(chunk), chunk_type: *b"fdAT", next_sequence_number: Some(next_sequence_number) };
+        reader.read_sequence_number()?;
+        Ok(reader)
+    }
+
+    fn read_sequence_number(&mut self) -> Result<()> {
+        if let Some(expected) = self.next_sequence_number {
+            let sequence_number = match self.chunk()?.read_u32() {
+                Err(Error::EndOfChunk(_)) => return Err(Error::Format("fdAT chunk is too short")),
+                result => result?,
+            };
+            info!("Sequence number: {}", sequence_number);
+            if sequence_number != expected {
+                warn!("Incorrect APNG sequence number");
+            }
+            self.next_sequence_number = Some(sequence_number.wrapping_add(1));
+        }
+        Ok(())
+    }
+
+    fn chunk(&mut self) -> Result<&mut ChunkReader<R>> {
+        self.chunk.as_mut().ok_or(Error::Format(USED_AFTER_ERROR))
+    }
+
+    pub fn next_sequence_number(&self) -> Option<u32> {
+        self.next_sequence_number
+    }
+
+    pub fn end(self) -> Result<ChunkReader<R>> {
+        self.chunk.ok_or(Error::Format(USED_AFTER_ERROR))
+    }
 }
 
-impl IdatReader {
-    pub fn new(chunk: ChunkReader) -> Result<IdatReader> {
-        Ok(IdatReader { chunk })
+impl<R> ByteReader for IdatReader<R> where R: Read {
+    fn read_buf(&mut self, buf: &mut [u8]) -> Result<()> {
+        let mut bytes_read = match self.chunk()?.read_buf(buf) {
+            Err(Error::EndOfChunk(len)) => len,
+            result => return result,
+        };
+        while bytes_read < buf.len() {
+            let old_chunk = self.chunk.take().ok_or(Error::Format(USED_AFTER_ERROR))?;
+            let crc_policy = old_chunk.crc_policy();
+            let (new_chunk, _, chunk_type) = ChunkReader::new(old_chunk.end()?, crc_policy)?;
+            self.chunk = Some(new_chunk);
+            if chunk_type != self.chunk_type {
+                return Err(Error::EndOfChunk(bytes_read));
+            }
+            self.read_sequence_number()?;
+            match self.chunk()?.read_buf(&mut buf[bytes_read ..]) {
+                Ok(()) => bytes_read = buf.len(),
+                Err(Error::EndOfChunk(len)) => bytes_read += len,
+                result => return result,
+            }
+        }
+        Ok(())
     }
 
-    pub fn end(self) -> Result<ChunkReader> {
-        Ok(self.chunk)
+    fn read_available(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.chunk()?.read_available(buf)
     }
 }
 
-impl ByteReader for IdatReader {
-    fn read_buf(&mut self, len: u32) -> Result<Box<[u8]>> {
-        match self.chunk.read_buf(len) {
-            Err(Error::EndOfChunk(data)) => {
-                let len = len as usize;
-                let mut buf = vec![0; len].into_boxed_slice();
-                for i in 0 .. data.len() {
-                    buf[i] = data[i];
-                }
-                let mut bytes_read = data.len();
-                while bytes_read < len {
-                    let chunk_type = unsafe {
-                        let old_chunk = std::ptr::read(&mut self.chunk);
-                        let (new_chunk, _, chunk_type) = ChunkReader::new(old_chunk.end()?)?;
-                        std::ptr::write(&mut self.chunk, new_chunk);
-                        chunk_type
-                    };
-                    if *chunk_type != *b"IDAT" {
-                        let mut data = vec![0; bytes_read].into_boxed_slice();
-                        for i in 0 .. bytes_read {
-                            data[i] = buf[i];
-                        }
-                        return Err(Error::EndOfChunk(data));
-                    }
-                    match self.chunk.read_buf((len - bytes_read) as u32) {
-                        Ok(data) | Err(Error::EndOfChunk(data)) => {
-                            for i in 0 .. len {
-                                buf[bytes_read + i] = data[i];
-                            }
-                            bytes_read += data.len();
-                        },
-                        result => return result,
-                    }
-                }
-                Ok(buf)
-            },
-            result => result,
+#[cfg(test)]
+mod tests {
+    use crate::chunk::chunk_bytes;
+    use crate::decoder::PNG_SIG;
+    use crate::deflate;
+    use crate::Decoder;
+    use crate::Error;
+    use crate::Result;
+    use std::io::BufReader;
+    use std::io::Cursor;
+    use std::io::Read;
+
+    const SIZE: usize = 10;
+    const IDAT_LEN: usize = 10;
+    // Offset of the first IDAT chunk, after the signature and IHDR chunk
+    const IDAT_START: usize = 8 + 25;
+
+    // 8-bit grayscale image with its image data split over IDAT chunks of IDAT_LEN bytes
+    fn split_idat_png() -> Vec<u8> {
+        let mut png = PNG_SIG.to_vec();
+        let mut ihdr = (SIZE as u32).to_be_bytes().to_vec();
+        ihdr.extend_from_slice(&(SIZE as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[8, 0, 0, 0, 0]);
+        png.extend(chunk_bytes(b"IHDR", &ihdr));
+        let scanlines: Vec<u8> = (0 .. SIZE * (SIZE + 1)).map(|i| if i % (SIZE + 1) == 0 { 0 } else { i as u8 }).collect();
+        for data in deflate::write_zlib(&scanlines, 0).chunks(IDAT_LEN) {
+            png.extend(chunk_bytes(b"IDAT", data));
         }
+        png.extend(chunk_bytes(b"IEND", &[]));
+        png
+    }
+
+    // Decodes through a buffered boxed reader, as the tools do, so that a double drop of the reader would be caught
+    fn decode(png: Vec<u8>) -> Result<()> {
+        let file: Box<dyn Read> = Box::new(Cursor::new(png));
+        let decoder = Decoder::new(BufReader::new(file))?;
+        let mut pixels = vec![0; SIZE * SIZE * 4];
+        decoder.decode(&mut pixels, SIZE * 4)?;
+        Ok(())
+    }
+
+    #[test]
+    fn split_image_data() {
+        assert!(decode(split_idat_png()).is_ok());
+    }
+
+    #[test]
+    fn truncated_in_second_idat_header() {
+        let mut png = split_idat_png();
+        png.truncate(IDAT_START + 12 + IDAT_LEN + 2);
+        assert!(matches!(decode(png), Err(Error::IO(_))));
+    }
+
+    #[test]
+    fn crc_mismatch_in_second_idat() {
+        let mut png = split_idat_png();
+        png[IDAT_START + 2 * (12 + IDAT_LEN) - 1] ^= 0x01;
+        assert!(matches!(decode(png), Err(Error::Checksum(_))));
     }
 }
@@ -1,11 +1,34 @@
 use crate::chunk::ChunkReader;
+use crate::chunk::CrcPolicy;
 use crate::file::ByteReader;
 use crate::Error;
 use crate::Result;
-use std::fs::File;
+use std::io::Read;
 
 pub type Palette = Box<[(u8, u8, u8)]>;
 
+// Approximate usage frequency of each palette entry from a hIST chunk
+pub type Histogram = Box<[u16]>;
+
+// Entry of a suggested palette, with samples at the sample depth of the palette
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SuggestedPaletteEntry {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+    pub alpha: u16,
+    pub frequency: u16,
+}
+
+// Suggested palette from an sPLT chunk, for displays which cannot show the full range of colors
+#[derive(Clone, Debug)]
+pub struct SuggestedPalette {
+    pub name: String,
+    pub sample_depth: u8,
+    pub entries: Box<[SuggestedPaletteEntry]>,
+}
+
+#[derive(Clone)]
 pub enum ColorMode {
     Grayscale1,
     Grayscale2,
@@ -45,6 +68,42 @@ impl ColorMode {
             RGBA16 => 64,
         }
     }
+
+    pub fn bit_depth(&self) -> u8 {
+        use ColorMode::*;
+        match self {
+            Grayscale1 | Palette1(_) => 1,
+            Grayscale2 | Palette2(_) => 2,
+            Grayscale4 | Palette4(_) => 4,
+            Grayscale8 | RGB8 | Palette8(_) | GrayscaleAlpha8 | RGBA8 => 8,
+            Grayscale16 | RGB16 | GrayscaleAlpha16 | RGBA16 => 16,
+        }
+    }
+
+    // Color type as stored in the IHDR chunk
+    pub fn color_type(&self) -> u8 {
+        use ColorMode::*;
+        match self {
+            Grayscale1 | Grayscale2 | Grayscale4 | Grayscale8 | Grayscale16 => 0,
+            RGB8 | RGB16 => 2,
+            Palette1(_) | Palette2(_) | Palette4(_) | Palette8(_) => 3,
+            GrayscaleAlpha8 | GrayscaleAlpha16 => 4,
+            RGBA8 | RGBA16 => 6,
+        }
+    }
+
+    // Number of bytes in a row of packed samples, not counting the filter type byte of a scanline
+    pub fn bytes_per_row(&self, width: u32) -> usize {
+        (width as usize * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    pub fn palette(&self) -> Option<&Palette> {
+        use ColorMode::*;
+        match self {
+            Palette1(palette) | Palette2(palette) | Palette4(palette) | Palette8(palette) => Some(palette),
+            _ => None,
+        }
+    }
 }
 
 pub enum PartialColorMode {
@@ -89,13 +148,80 @@ fn get_interlace_method(interlace_method: u8) -> Result<InterlaceMethod> {
     }
 }
 
-pub fn load_ihdr(file: File) -> Result<(File, u32, u32, PartialColorMode, InterlaceMethod)> {
-    let (mut chunk, _, chunk_type) = ChunkReader::new(file)?;
-    if *chunk_type != *b"IHDR" {
+pub fn load_palette<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Palette> where R: Read {
+    let mut palette = vec![(0, 0, 0); length as usize / 3].into_boxed_slice();
+    for v in palette.iter_mut() {
+        *v = (chunk.read_u8()?, chunk.read_u8()?, chunk.read_u8()?);
+    }
+    if !length.is_multiple_of(3) {
+        warn!("Number of bytes in PLTE chunk is not a multiple of 3");
+    }
+    Ok(palette)
+}
+
+// Returns None if the chunk does not match the palette, in which case it should be ignored
+pub fn load_hist<R>(chunk: &mut ChunkReader<R>, length: u32, palette_len: usize) -> Result<Option<Histogram>> where R: Read {
+    if length as usize != palette_len * 2 {
+        warn!("hIST chunk length does not match the number of palette entries");
+        return Ok(None);
+    }
+    let mut histogram = vec![0; palette_len].into_boxed_slice();
+    for v in histogram.iter_mut() {
+        *v = chunk.read_u16()?;
+    }
+    info!("Histogram: {:?}", histogram);
+    Ok(Some(histogram))
+}
+
+pub fn load_splt<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<SuggestedPalette>> where R: Read {
+    let name = match chunk.read_keyword()? {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    info!("Suggested palette name: {}", name);
+    let sample_depth = chunk.read_u8()?;
+    info!("Sample depth: {}", sample_depth);
+    let entry_size = match sample_depth {
+        8 => 6,
+        16 => 10,
+        _ => {
+            warn!("Invalid sPLT sample depth");
+            return Ok(None);
+        },
+    };
+    let data_length = match (length as usize).checked_sub(name.chars().count() + 2) {
+        Some(data_length) => data_length,
+        None => {
+            warn!("sPLT chunk is too short");
+            return Ok(None);
+        },
+    };
+    if data_length % entry_size != 0 {
+        warn!("sPLT chunk length is not a multiple of the entry size");
+        return Ok(None);
+    }
+    // The length is not trusted for reserving memory, since the entries may not actually be there
+    let mut entries = Vec::new();
+    for _ in 0 .. data_length / entry_size {
+        let (red, green, blue, alpha) = if sample_depth == 8 {
+            (chunk.read_u8()? as u16, chunk.read_u8()? as u16, chunk.read_u8()? as u16, chunk.read_u8()? as u16)
+        } else {
+            (chunk.read_u16()?, chunk.read_u16()?, chunk.read_u16()?, chunk.read_u16()?)
+        };
+        let frequency = chunk.read_u16()?;
+        info!("Entry: ({}, {}, {}, {}), frequency {}", red, green, blue, alpha, frequency);
+        entries.push(SuggestedPaletteEntry { red, green, blue, alpha, frequency });
+    }
+    Ok(Some(SuggestedPalette { name, sample_depth, entries: entries.into_boxed_slice() }))
+}
+
+pub fn load_ihdr<R>(file: R, crc_policy: CrcPolicy) -> Result<(R, u32, u32, PartialColorMode, InterlaceMethod)> where R: Read {
+    let (mut chunk, _, chunk_type) = ChunkReader::new(file, crc_policy)?;
+    if chunk_type != *b"IHDR" {
         return Err(Error::Format("First chunk is not IHDR"));
     }
     let width = chunk.read_u32()?;
-    println!("Width: {}", width);
+    info!("Width: {}", width);
     if width == 0 {
         return Err(Error::Format("Width is zero"));
     }
@@ -103,7 +229,7 @@ pub fn load_ihdr(file: File) -> Result<(File, u32, u32, PartialColorMode, Interl
         warn!("Width exceeds (2^32)-1");
     }
     let height = chunk.read_u32()?;
-    println!("Height: {}", height);
+    info!("Height: {}", height);
     if height == 0 {
         return Err(Error::Format("Height is zero"));
     }
@@ -111,12 +237,12 @@ pub fn load_ihdr(file: File) -> Result<(File, u32, u32, PartialColorMode, Interl
         warn!("Height exceeds (2^32)-1");
     }
     let bit_depth = chunk.read_u8()?;
-    println!("Bit depth: {}", bit_depth);
+    info!("Bit depth: {}", bit_depth);
     let color_type = chunk.read_u8()?;
-    println!("Color type: {}", color_type);
+    info!("Color type: {}", color_type);
     let partial_color_mode = get_color_mode(bit_depth, color_type)?;
     let compression_method = chunk.read_u8()?;
-    println!("Compression method: {}", compression_method);
+    info!("Compression method: {}", compression_method);
     if compression_method != 0 {
         return Err(Error::Format("Unrecognized compression method"));
     }
@@ -125,7 +251,7 @@ pub fn load_ihdr(file: File) -> Result<(File, u32, u32, PartialColorMode, Interl
         return Err(Error::Format("Unrecognized filter method"));
     }
     let interlace_method = chunk.read_u8()?;
-    println!("Interlace method: {}", interlace_method);
+    info!("Interlace method: {}", interlace_method);
     let interlace_method = get_interlace_method(interlace_method)?;
     let file = chunk.end()?;
     Ok((file, width, height, partial_color_mode, interlace_method))
@@ -0,0 +1,147 @@
+macro_rules! warn {
+    ( $f:expr $( , $x:expr )* ) => {
+        eprintln!(concat!("! Warning: ", $f) $( , $x )*)
+    };
+}
+
+macro_rules! info {
+    ( $f:expr $( , $x:expr )* ) => {
+        if crate::verbose() {
+            println!($f $( , $x )*);
+        }
+    };
+}
+
+mod apng;
+mod bkgd;
+mod chunk;
+mod color;
+mod crc;
+mod decoder;
+mod deflate;
+mod encoder;
+mod file;
+mod exif;
+mod filter;
+mod gamma;
+mod hdr;
+mod icc;
+mod idat;
+mod ihdr;
+mod phys;
+mod sbit;
+mod text;
+mod time;
+mod trns;
+mod zlib;
+
+pub use crate::apng::AnimationControl;
+pub use crate::apng::BlendOp;
+pub use crate::apng::DisposeOp;
+pub use crate::apng::Frame;
+pub use crate::apng::FrameControl;
+pub use crate::apng::Frames;
+pub use crate::bkgd::Background;
+pub use crate::chunk::ChecksumPolicy;
+pub use crate::chunk::CrcPolicy;
+pub use crate::color::Chromaticities;
+pub use crate::color::IccProfile;
+pub use crate::color::RenderingIntent;
+pub use crate::decoder::Decoder;
+pub use crate::decoder::ImageInfo;
+pub use crate::encoder::Encoder;
+pub use crate::exif::Exif;
+pub use crate::exif::ExifTags;
+pub use crate::exif::Orientation;
+pub use crate::filter::FilterStrategy;
+pub use crate::filter::FilterType;
+pub use crate::hdr::CodingIndependentCodePoints;
+pub use crate::hdr::ContentLightLevel;
+pub use crate::hdr::MasteringDisplay;
+pub use crate::ihdr::ColorMode;
+pub use crate::ihdr::Histogram;
+pub use crate::ihdr::InterlaceMethod;
+pub use crate::ihdr::Palette;
+pub use crate::ihdr::SuggestedPalette;
+pub use crate::ihdr::SuggestedPaletteEntry;
+pub use crate::phys::PhysicalDimensions;
+pub use crate::phys::Unit;
+pub use crate::sbit::SignificantBits;
+pub use crate::text::TextEntry;
+pub use crate::text::TextKind;
+pub use crate::time::Timestamp;
+pub use crate::trns::Transparency;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    #[cfg(feature = "viewer")]
+    Sdl(String),
+    #[cfg(feature = "viewer")]
+    SdlWindow(sdl2::video::WindowBuildError),
+    EndOfChunk(usize),
+    Format(&'static str),
+    Checksum(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IO(err)
+    }
+}
+
+#[cfg(feature = "viewer")]
+impl From<sdl2::video::WindowBuildError> for Error {
+    fn from(err: sdl2::video::WindowBuildError) -> Self {
+        Error::SdlWindow(err)
+    }
+}
+
+#[cfg(feature = "viewer")]
+impl From<sdl2::IntegerOrSdlError> for Error {
+    fn from(err: sdl2::IntegerOrSdlError) -> Self {
+        match err {
+            sdl2::IntegerOrSdlError::IntegerOverflows(s, _) => Error::Sdl(s.to_string()),
+            sdl2::IntegerOrSdlError::SdlError(s) => Error::Sdl(s),
+        }
+    }
+}
+
+#[cfg(feature = "viewer")]
+impl From<sdl2::render::UpdateTextureError> for Error {
+    fn from(err: sdl2::render::UpdateTextureError) -> Self {
+        match err {
+            sdl2::render::UpdateTextureError::SdlError(s) => Error::Sdl(s),
+            err => Error::Sdl(err.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "viewer")]
+impl From<sdl2::render::TextureValueError> for Error {
+    fn from(err: sdl2::render::TextureValueError) -> Self {
+        match err {
+            sdl2::render::TextureValueError::WidthOverflows(_) => Error::Sdl("Texture width overflow".to_string()),
+            sdl2::render::TextureValueError::HeightOverflows(_) => Error::Sdl("Texture height overflow".to_string()),
+            sdl2::render::TextureValueError::WidthMustBeMultipleOfTwoForFormat(_, _) =>
+                Error::Sdl("Texture width must be a multiple of two for format".to_string()),
+            sdl2::render::TextureValueError::SdlError(s) => Error::Sdl(s),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+// Enables printing the contents of the file to standard output while decoding
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+fn verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed)
+}
@@ -1,196 +1,369 @@
-#[macro_use]
-macro_rules! warn {
-    ( $f:expr $( , $x:expr )* ) => {
-        eprintln!(concat!("! Warning: ", $f), $($x)*);
-    };
-}
-
-mod chunk;
-mod file;
-mod filter;
-mod idat;
-mod ihdr;
-mod zlib;
-
-use crate::chunk::ChunkReader;
-use crate::file::ByteReader;
-use crate::idat::IdatReader;
-use crate::ihdr::PartialColorMode;
-use crate::ihdr::ColorMode;
-use crate::ihdr::InterlaceMethod;
+use png::Decoder;
+use png::Error;
+use png::Frame;
+use png::Frames;
+use png::ImageInfo;
+use png::Orientation;
+use png::Result;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
 use std::env;
 use std::fs::File;
 use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+const DISPLAY_GAMMA: f64 = 2.2;
+
+// Frames with delays this short are shown for the default delay instead, as web browsers do
+const MIN_FRAME_DELAY: f64 = 0.011;
+const DEFAULT_FRAME_DELAY: f64 = 0.1;
+
+// Time to sleep between iterations of the event loop
+const EVENT_LOOP_SLEEP: Duration = Duration::from_millis(5);
+
+// Glyphs of the frame counter overlay for the digits and '/', as rows of 3 pixels
+const GLYPHS: [[u8; 5]; 11] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b001, 0b001, 0b010, 0b100, 0b100],
+];
+const GLYPH_SCALE: i32 = 3;
+
+// Size and colors of the checkerboard squares drawn behind transparent images
+const CHECKER_SIZE: usize = 8;
+const CHECKER_LIGHT: u8 = 0xCC;
+const CHECKER_DARK: u8 = 0x99;
+
+// What transparent pixels are composited over
+#[derive(Copy, Clone)]
+enum Backdrop {
+    Color(u8, u8, u8),
+    Checkerboard,
+}
+
+impl Backdrop {
+    fn parse(name: &str) -> Result<Backdrop> {
+        match name {
+            "checker" => Ok(Backdrop::Checkerboard),
+            "black" => Ok(Backdrop::Color(0x00, 0x00, 0x00)),
+            "white" => Ok(Backdrop::Color(0xFF, 0xFF, 0xFF)),
+            _ => {
+                let color = match u32::from_str_radix(name.trim_start_matches('#'), 16) {
+                    Ok(color) if name.trim_start_matches('#').len() == 6 => color,
+                    _ => return Err(Error::Format("Background is not checker, black, white or an RRGGBB color")),
+                };
+                Ok(Backdrop::Color((color >> 16) as u8, (color >> 8) as u8, color as u8))
+            },
+        }
+    }
+
+    fn color_at(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        match *self {
+            Backdrop::Color(r, g, b) => (r, g, b),
+            Backdrop::Checkerboard => {
+                let v = if (x / CHECKER_SIZE + y / CHECKER_SIZE) & 1 == 0 { CHECKER_LIGHT } else { CHECKER_DARK };
+                (v, v, v)
+            },
+        }
+    }
+}
 
-#[derive(Debug)]
-pub enum Error {
-    IO(io::Error),
-    Sdl(String),
-    SdlWindow(sdl2::video::WindowBuildError),
-    EndOfChunk(Box<[u8]>),
-    Format(&'static str),
+// Blends RGBA pixels over the backdrop into opaque pixels, transformed to the given orientation
+fn composite(pixels: &[u8], composited: &mut [u8], width: usize, height: usize, orientation: Orientation, backdrop: Backdrop) {
+    let display_width = if orientation.swaps_axes() { height } else { width };
+    for (i, pixel) in pixels.chunks(4).enumerate() {
+        let (x, y) = orientation.apply(i % width, i / width, width, height);
+        let (r, g, b) = backdrop.color_at(x, y);
+        let alpha = pixel[3] as u32;
+        let blend = |fg: u8, bg: u8| ((fg as u32 * alpha + bg as u32 * (255 - alpha) + 127) / 255) as u8;
+        let j = (y * display_width + x) * 4;
+        composited[j] = blend(pixel[0], r);
+        composited[j + 1] = blend(pixel[1], g);
+        composited[j + 2] = blend(pixel[2], b);
+        composited[j + 3] = 0xFF;
+    }
 }
 
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Self {
-        Error::IO(err)
+fn exif_orientation(info: &ImageInfo, apply_orientation: bool) -> Orientation {
+    match &info.exif {
+        Some(exif) if apply_orientation => exif.tags.orientation.unwrap_or(Orientation::Normal),
+        _ => Orientation::Normal,
     }
 }
 
-impl From<sdl2::video::WindowBuildError> for Error {
-    fn from(err: sdl2::video::WindowBuildError) -> Self {
-        Error::SdlWindow(err)
+// Size of the image when displayed in the given orientation, and of the window showing it,
+// which differs if the aspect ratio is corrected for non-square pixels
+fn display_size(info: &ImageInfo, orientation: Orientation, correct_aspect: bool) -> ((u32, u32), (u32, u32)) {
+    let (width, height) = (info.width, info.height);
+    let (window_width, window_height) = match info.physical_dimensions {
+        Some(dimensions) if correct_aspect => {
+            let ratio = dimensions.pixel_aspect_ratio();
+            if ratio > 1.0 {
+                ((width as f64 * ratio).round() as u32, height)
+            } else {
+                (width, (height as f64 / ratio).round() as u32)
+            }
+        },
+        _ => (width, height),
+    };
+    if orientation.swaps_axes() {
+        ((height, width), (window_height, window_width))
+    } else {
+        ((width, height), (window_width, window_height))
     }
 }
 
-impl From<sdl2::IntegerOrSdlError> for Error {
-    fn from(err: sdl2::IntegerOrSdlError) -> Self {
-        match err {
-            sdl2::IntegerOrSdlError::IntegerOverflows(s, _) => Error::Sdl(s.to_string()),
-            sdl2::IntegerOrSdlError::SdlError(s) => Error::Sdl(s),
+// Builds the window title from the file name and, if enabled, the resolution and modification time of the image
+fn window_title(filename: &str, info: &ImageInfo, show_info: bool) -> String {
+    let mut title = filename.to_string();
+    if show_info {
+        if let Some((x, y)) = info.physical_dimensions.as_ref().and_then(|dimensions| dimensions.dpi()) {
+            if x.round() == y.round() {
+                title += &format!(" - {:.0} DPI", x);
+            } else {
+                title += &format!(" - {:.0}x{:.0} DPI", x, y);
+            }
+        }
+        if let Some(time) = &info.modification_time {
+            title += &format!(" - modified {}", time);
         }
     }
+    title
 }
 
-impl From<sdl2::render::TextureValueError> for Error {
-    fn from(err: sdl2::render::TextureValueError) -> Self {
-        match err {
-            sdl2::render::TextureValueError::WidthOverflows(_) => Error::Sdl("Texture width overflow".to_string()),
-            sdl2::render::TextureValueError::HeightOverflows(_) => Error::Sdl("Texture height overflow".to_string()),
-            sdl2::render::TextureValueError::WidthMustBeMultipleOfTwoForFormat(_, _) =>
-                Error::Sdl("Texture width must be a multiple of two for format".to_string()),
-            sdl2::render::TextureValueError::SdlError(s) => Error::Sdl(s),
+// Draws the text, which may only contain digits and '/', in the top left corner on a black box
+fn draw_overlay(canvas: &mut sdl2::render::WindowCanvas, text: &str) -> Result<()> {
+    let advance = 4 * GLYPH_SCALE;
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.fill_rect(Rect::new(0, 0, (text.len() as i32 * advance + GLYPH_SCALE) as u32, (7 * GLYPH_SCALE) as u32)).map_err(Error::Sdl)?;
+    canvas.set_draw_color(Color::RGB(0xFF, 0xFF, 0xFF));
+    for (i, c) in text.chars().enumerate() {
+        let glyph = match c.to_digit(10) {
+            Some(digit) => GLYPHS[digit as usize],
+            None => GLYPHS[10],
+        };
+        for (y, row) in glyph.iter().enumerate() {
+            for x in 0 .. 3 {
+                if row & (0b100 >> x) != 0 {
+                    let px = GLYPH_SCALE + i as i32 * advance + x * GLYPH_SCALE;
+                    let py = GLYPH_SCALE + y as i32 * GLYPH_SCALE;
+                    canvas.fill_rect(Rect::new(px, py, GLYPH_SCALE as u32, GLYPH_SCALE as u32)).map_err(Error::Sdl)?;
+                }
+            }
         }
     }
+    Ok(())
 }
 
-type Result<T> = std::result::Result<T, Error>;
+// Frames of an animation decoded so far, kept so that stepping back does not require decoding again
+struct Animation<R> where R: Read {
+    frames: Frames<R>,
+    decoded: Vec<Frame>,
+    complete: bool,
+}
 
-const PNG_SIG : [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+impl<R> Animation<R> where R: Read {
+    // Returns the frame at the index, decoding frames up to it if needed, or None if the animation is shorter
+    fn frame(&mut self, index: usize) -> Result<Option<&Frame>> {
+        while self.decoded.len() <= index && !self.complete {
+            match self.frames.next() {
+                Some(frame) => self.decoded.push(frame?),
+                None => self.complete = true,
+            }
+        }
+        Ok(self.decoded.get(index))
+    }
 
-fn decompressed_data_length(width: u32, height: u32, color_mode: &ColorMode, interlace_method: InterlaceMethod) -> usize {
-    match interlace_method {
-        InterlaceMethod::NoInterlace => ((width as usize * color_mode.bits_per_pixel() + 7) / 8 + 1) * height as usize,
-        InterlaceMethod::Adam7 => std::todo!(),
+    // Number of frames, if all of them have been decoded
+    fn len(&self) -> Option<usize> {
+        if self.complete { Some(self.decoded.len()) } else { None }
     }
 }
 
+fn frame_delay(frame: &Frame) -> Duration {
+    let delay = frame.control.delay();
+    Duration::from_secs_f64(if delay < MIN_FRAME_DELAY { DEFAULT_FRAME_DELAY } else { delay })
+}
+
+// Usage: png [--aspect] [--info] [--orient] [--background=checker|black|white|RRGGBB] FILE
+// --aspect stretches the image to correct for non-square pixels, and --info shows the DPI and modification time.
+// --orient rotates and mirrors the image as given by the orientation in its EXIF data.
+// Animations can be paused and resumed with space, and stepped through a frame at a time with the arrow keys.
+// Transparent images are composited over their bKGD color if they have one, or else the chosen background.
 fn main() -> Result<()> {
-    let mut args = env::args();
-    if args.len() != 2 {
-        return Err(Error::Format("Invalid number of arguments"));
+    let mut filename = None;
+    let mut correct_aspect = false;
+    let mut show_info = false;
+    let mut apply_orientation = false;
+    let mut backdrop = Backdrop::Checkerboard;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--aspect" => correct_aspect = true,
+            "--info" => show_info = true,
+            "--orient" => apply_orientation = true,
+            _ if arg.starts_with("--background=") => backdrop = Backdrop::parse(&arg["--background=".len() ..])?,
+            _ if arg.starts_with("--") => return Err(Error::Format("Unrecognized option")),
+            _ if filename.is_none() => filename = Some(arg),
+            _ => return Err(Error::Format("Invalid number of arguments")),
+        }
     }
-    let filename = args.nth(1).unwrap();
-    let mut file = File::open(&filename)?;
+    let filename = filename.ok_or(Error::Format("Invalid number of arguments"))?;
+    let file: Box<dyn Read> = if filename == "-" { Box::new(io::stdin()) } else { Box::new(File::open(&filename)?) };
+    png::set_verbose(true);
 
     let sdl_context = sdl2::init().map_err(Error::Sdl)?;
     let video_subsystem = sdl_context.video().map_err(Error::Sdl)?;
 
-    let sig = file.read_buf(8)?;
-    println!("Signature: {:02X?}", sig);
-    if *sig != PNG_SIG {
-        return Err(Error::Format("Invalid PNG signature"));
+    let mut decoder = Decoder::new(BufReader::new(file))?;
+    decoder.set_display_gamma(DISPLAY_GAMMA);
+    decoder.set_srgb_output(true);
+    decoder.set_tone_mapping(true);
+    let width = decoder.info().width;
+    let height = decoder.info().height;
+    let mut orientation = exif_orientation(decoder.info(), apply_orientation);
+    let ((display_width, display_height), (window_width, window_height)) = display_size(decoder.info(), orientation, correct_aspect);
+    let title = window_title(&filename, decoder.info(), show_info);
+    let mut canvas = video_subsystem.window(&title, window_width, window_height).build()?.into_canvas().build()?;
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator.create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGBA32, display_width, display_height)?;
+
+    let info = decoder.info();
+    if let Some((r, g, b)) = info.background.and_then(|background| background.to_rgb8(&info.color_mode)) {
+        backdrop = Backdrop::Color(r, g, b);
     }
 
-    let (mut file, width, height, mut partial_color_mode, interlace_method) = ihdr::load_ihdr(file)?;
-    let mut canvas = video_subsystem.window(&filename, width, height).build()?.into_canvas().build()?;
-    let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator.create_texture_streaming(sdl2::pixels::PixelFormatEnum::ARGB8888, width, height)?;
-    let mut after_plte = false;
-    let mut after_idat = false;
-
-    loop {
-        let (mut chunk, length, chunk_type) = ChunkReader::new(file)?;
-        match &*chunk_type {
-            b"IHDR" => {
-                warn!("Multiple IHDR chunks");
-            },
-            b"PLTE" => {
-                if after_plte {
-                    warn!("Multiple PLTE chunks");
-                }
-                if after_idat {
-                    warn!("PLTE chunk after IDAT chunk");
-                }
-                after_plte = true;
-                let mut palette = vec![(0, 0, 0); length as usize / 3].into_boxed_slice();
-                for (_, v) in palette.iter_mut().enumerate() {
-                    *v = (chunk.read_u8()?, chunk.read_u8()?, chunk.read_u8()?);
-                }
-                if length % 3 != 0 {
-                    warn!("Number of bytes in PLTE chunk is not a multiple of 3");
-                }
-                match partial_color_mode {
-                    PartialColorMode::Full(ColorMode::Grayscale1) |
-                    PartialColorMode::Full(ColorMode::Grayscale2) |
-                    PartialColorMode::Full(ColorMode::Grayscale4) |
-                    PartialColorMode::Full(ColorMode::Grayscale8) |
-                    PartialColorMode::Full(ColorMode::Grayscale16) |
-                    PartialColorMode::Full(ColorMode::GrayscaleAlpha8) |
-                    PartialColorMode::Full(ColorMode::GrayscaleAlpha16) =>
-                        warn!("PLTE chunk with grayscale color"),
-                    PartialColorMode::Partial(f) => partial_color_mode = PartialColorMode::Full(f(palette)),
+    let pitch = width as usize * 4;
+    let mut composited = vec![0; pitch * height as usize].into_boxed_slice();
+    let mut event_pump = sdl_context.event_pump().map_err(Error::Sdl)?;
+
+    if let Some(animation_control) = decoder.info().animation {
+        let mut animation = Animation { frames: decoder.frames()?, decoded: Vec::new(), complete: false };
+        let mut index = 0;
+        let mut plays = 0;
+        let mut paused = false;
+        let mut shown = None;
+        let mut next_frame_time = Instant::now();
+        let mut title_updated = false;
+        'play: loop {
+            let mut step = None;
+            for event in event_pump.poll_iter() {
+                match event {
+                    Event::Quit {..} => break 'play,
+                    Event::KeyDown { keycode: Some(Keycode::Space), .. } => {
+                        paused = !paused;
+                        next_frame_time = Instant::now();
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Right), .. } => {
+                        paused = true;
+                        step = Some(true);
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Left), .. } => {
+                        paused = true;
+                        step = Some(false);
+                    },
                     _ => (),
                 }
-                let max_palette = match partial_color_mode {
-                    PartialColorMode::Full(ColorMode::Palette1(_)) => 2,
-                    PartialColorMode::Full(ColorMode::Palette2(_)) => 4,
-                    PartialColorMode::Full(ColorMode::Palette4(_)) => 16,
-                    _ => 256,
-                };
-                if length / 3 > max_palette {
-                    warn!("Palette exceeds maximum length for color type");
-                }
-            },
-            b"IDAT" => {
-                if after_idat {
-                    warn!("More IDAT chunks");
-                }
-                let color_mode = match partial_color_mode {
-                    PartialColorMode::Full(ref mode) => mode,
-                    PartialColorMode::Partial(_) => return Err(Error::Format("No PLTE chunk befor IDAT with indexed colors")),
-                };
-                let mut buf = vec![0; decompressed_data_length(width, height, color_mode, interlace_method)].into_boxed_slice();
-                chunk = zlib::read_zlib(IdatReader::new(chunk)?, &mut buf)?.end()?;
-                texture.with_lock(None,
-                    |pixels, pitch| filter::unfilter_uninterlace(&mut buf, pixels, pitch, width, height, color_mode, interlace_method))
-                    .map_err(Error::Sdl)??;
-                canvas.copy(&texture, None, None).map_err(Error::Sdl)?;
-                canvas.present();
-                after_idat = true;
-            },
-            b"IEND" => {
-                if length != 0 {
-                    warn!("IEND chunk has nonzero length");
-                }
-                if !after_idat {
-                    warn!("No IDAT chunk before IEND chunk");
-                }
-            },
-            _ => {
-                // TODO warn on invalid chunk types
-                if chunk_type[0] & 0x20 == 0 {
-                    warn!("Unrecognized critical chunk");
+            }
+            match step {
+                Some(true) => {
+                    index += 1;
+                    if animation.frame(index)?.is_none() {
+                        index = 0;
+                    }
+                },
+                Some(false) => {
+                    if index == 0 {
+                        // Going back from the first frame requires knowing the last one
+                        while animation.len().is_none() {
+                            animation.frame(animation.decoded.len())?;
+                        }
+                        index = animation.len().unwrap_or(1).saturating_sub(1);
+                    } else {
+                        index -= 1;
+                    }
+                },
+                None if !paused && Instant::now() >= next_frame_time && shown == Some(index) => {
+                    if animation.frame(index + 1)?.is_some() {
+                        index += 1;
+                    } else if animation_control.num_plays == 0 || plays + 1 < animation_control.num_plays {
+                        plays += 1;
+                        index = 0;
+                    } else {
+                        // The animation stays on its last frame after the last play
+                        paused = true;
+                    }
+                },
+                None => (),
+            }
+            // Each frame is already composited onto the full image, so any frame can be shown directly
+            if shown != Some(index) {
+                if let Some(frame) = animation.frame(index)? {
+                    composite(&frame.pixels, &mut composited, width as usize, height as usize, orientation, backdrop);
+                    texture.update(None, &composited, display_width as usize * 4)?;
+                    next_frame_time = Instant::now() + frame_delay(frame);
                 }
-            },
-        }
-        file = chunk.end()?;
-        if *chunk_type == *b"IEND" {
-            // TODO check for EOF
-            break;
+                shown = Some(index);
+            }
+            // The modification time may be stored after the last frame
+            if animation.complete && !title_updated {
+                let title = window_title(&filename, animation.frames.info(), show_info);
+                canvas.window_mut().set_title(&title).map_err(|err| Error::Sdl(err.to_string()))?;
+                title_updated = true;
+            }
+            canvas.copy(&texture, None, None).map_err(Error::Sdl)?;
+            let counter = match animation.len() {
+                Some(len) => format!("{}/{}", index + 1, len),
+                None => format!("{}/{}", index + 1, animation_control.num_frames),
+            };
+            draw_overlay(&mut canvas, &counter)?;
+            canvas.present();
+            thread::sleep(EVENT_LOOP_SLEEP);
         }
+        return Ok(());
+    }
+
+    let mut pixels = vec![0; pitch * height as usize].into_boxed_slice();
+    let info = decoder.decode_progressive(&mut pixels, pitch, |pixels| {
+        composite(pixels, &mut composited, width as usize, height as usize, orientation, backdrop);
+        texture.update(None, &composited, display_width as usize * 4)?;
+        canvas.copy(&texture, None, None).map_err(Error::Sdl)?;
+        canvas.present();
+        Ok(())
+    })?;
+    // The modification time and EXIF data may be stored after the image data
+    canvas.window_mut().set_title(&window_title(&filename, &info, show_info)).map_err(|err| Error::Sdl(err.to_string()))?;
+    if exif_orientation(&info, apply_orientation) != orientation {
+        orientation = exif_orientation(&info, apply_orientation);
+        let ((display_width, display_height), (window_width, window_height)) = display_size(&info, orientation, correct_aspect);
+        canvas.window_mut().set_size(window_width, window_height).map_err(|err| Error::Sdl(err.to_string()))?;
+        texture = texture_creator.create_texture_streaming(sdl2::pixels::PixelFormatEnum::RGBA32, display_width, display_height)?;
+        composite(&pixels, &mut composited, width as usize, height as usize, orientation, backdrop);
+        texture.update(None, &composited, display_width as usize * 4)?;
     }
 
-    let mut event_pump = sdl_context.event_pump().map_err(Error::Sdl)?;
     'wait: loop {
         for event in event_pump.poll_iter() {
-            match event {
-                sdl2::event::Event::Quit {..} => break 'wait,
-                _ => (),
+            if let Event::Quit {..} = event {
+                break 'wait;
             }
         }
         canvas.copy(&texture, None, None).map_err(Error::Sdl)?;
         canvas.present();
+        thread::sleep(EVENT_LOOP_SLEEP);
     }
 
     Ok(())
@@ -0,0 +1,60 @@
+use crate::chunk::ChunkReader;
+use crate::file::ByteReader;
+use crate::Result;
+use std::io::Read;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Unknown,
+    Meter,
+}
+
+// Pixels per unit along each axis from a pHYs chunk.
+// With an unknown unit only the ratio between the two is meaningful.
+#[derive(Copy, Clone, Debug)]
+pub struct PhysicalDimensions {
+    pub x: u32,
+    pub y: u32,
+    pub unit: Unit,
+}
+
+impl PhysicalDimensions {
+    // Width of a pixel divided by its height
+    pub fn pixel_aspect_ratio(&self) -> f64 {
+        self.y as f64 / self.x as f64
+    }
+
+    // Horizontal and vertical resolution in dots per inch, if the unit is known
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        match self.unit {
+            Unit::Meter => Some((self.x as f64 * 0.0254, self.y as f64 * 0.0254)),
+            Unit::Unknown => None,
+        }
+    }
+}
+
+// Returns None if the chunk is invalid, in which case it should be ignored
+pub fn load_phys<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<PhysicalDimensions>> where R: Read {
+    if length != 9 {
+        warn!("pHYs chunk has incorrect length");
+        return Ok(None);
+    }
+    let x = chunk.read_u32()?;
+    let y = chunk.read_u32()?;
+    let unit = chunk.read_u8()?;
+    info!("Pixels per unit: {} x {}", x, y);
+    info!("Unit: {}", unit);
+    let unit = match unit {
+        0 => Unit::Unknown,
+        1 => Unit::Meter,
+        _ => {
+            warn!("Invalid pHYs unit");
+            return Ok(None);
+        },
+    };
+    if x == 0 || y == 0 {
+        warn!("Pixels per unit is zero");
+        return Ok(None);
+    }
+    Ok(Some(PhysicalDimensions { x, y, unit }))
+}
@@ -0,0 +1,73 @@
+use crate::chunk::ChunkReader;
+use crate::file::ByteReader;
+use crate::ihdr::ColorMode;
+use crate::ihdr::PartialColorMode;
+use crate::Result;
+use std::io::Read;
+
+// Number of significant bits in each channel from an sBIT chunk.
+// For indexed color these apply to the palette entries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SignificantBits {
+    Gray(u8),
+    RGB(u8, u8, u8),
+    GrayAlpha(u8, u8),
+    RGBA(u8, u8, u8, u8),
+}
+
+impl SignificantBits {
+    pub fn color(&self) -> (u8, u8, u8) {
+        match *self {
+            SignificantBits::Gray(gray) | SignificantBits::GrayAlpha(gray, _) => (gray, gray, gray),
+            SignificantBits::RGB(r, g, b) | SignificantBits::RGBA(r, g, b, _) => (r, g, b),
+        }
+    }
+
+    pub fn alpha(&self) -> Option<u8> {
+        match *self {
+            SignificantBits::GrayAlpha(_, alpha) | SignificantBits::RGBA(_, _, _, alpha) => Some(alpha),
+            _ => None,
+        }
+    }
+}
+
+// Returns None if the chunk is not valid for the color mode, in which case it should be ignored
+pub fn load_sbit<R>(chunk: &mut ChunkReader<R>, length: u32, partial_color_mode: &PartialColorMode) -> Result<Option<SignificantBits>> where R: Read {
+    use ColorMode::*;
+    // Number of channels and the sample depth they are limited by
+    let (channels, sample_depth) = match partial_color_mode {
+        PartialColorMode::Full(Grayscale1) => (1, 1),
+        PartialColorMode::Full(Grayscale2) => (1, 2),
+        PartialColorMode::Full(Grayscale4) => (1, 4),
+        PartialColorMode::Full(Grayscale8) => (1, 8),
+        PartialColorMode::Full(Grayscale16) => (1, 16),
+        PartialColorMode::Full(RGB8) => (3, 8),
+        PartialColorMode::Full(RGB16) => (3, 16),
+        PartialColorMode::Full(Palette1(_)) | PartialColorMode::Full(Palette2(_)) |
+        PartialColorMode::Full(Palette4(_)) | PartialColorMode::Full(Palette8(_)) |
+        PartialColorMode::Partial(_) => (3, 8),
+        PartialColorMode::Full(GrayscaleAlpha8) => (2, 8),
+        PartialColorMode::Full(GrayscaleAlpha16) => (2, 16),
+        PartialColorMode::Full(RGBA8) => (4, 8),
+        PartialColorMode::Full(RGBA16) => (4, 16),
+    };
+    if length != channels {
+        warn!("sBIT chunk has incorrect length for color type");
+        return Ok(None);
+    }
+    let mut bits = [0; 4];
+    for v in bits.iter_mut().take(channels as usize) {
+        *v = chunk.read_u8()?;
+    }
+    info!("Significant bits: {:?}", &bits[.. channels as usize]);
+    if bits[.. channels as usize].iter().any(|&v| v == 0 || v > sample_depth) {
+        warn!("Significant bits are zero or exceed sample depth");
+        return Ok(None);
+    }
+    Ok(Some(match channels {
+        1 => SignificantBits::Gray(bits[0]),
+        2 => SignificantBits::GrayAlpha(bits[0], bits[1]),
+        3 => SignificantBits::RGB(bits[0], bits[1], bits[2]),
+        _ => SignificantBits::RGBA(bits[0], bits[1], bits[2], bits[3]),
+    }))
+}
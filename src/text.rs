@@ -0,0 +1,205 @@
+use crate::chunk::ChunkReader;
+use crate::file::ByteReader;
+use crate::file::SliceReader;
+use crate::zlib;
+use crate::Error;
+use crate::Result;
+use std::io::Read;
+
+// Limit on the size of decompressed text, so that a malicious file cannot exhaust memory
+const MAX_TEXT_SIZE: usize = 1 << 24;
+
+#[derive(Clone, Debug)]
+pub enum TextKind {
+    // Uncompressed Latin-1 text from a tEXt chunk
+    Latin1,
+    // Compressed Latin-1 text from a zTXt chunk
+    Compressed,
+    // UTF-8 text from an iTXt chunk
+    International { language: String, translated_keyword: String, compressed: bool },
+}
+
+#[derive(Clone, Debug)]
+pub struct TextEntry {
+    pub keyword: String,
+    pub text: String,
+    pub kind: TextKind,
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn print_entry(entry: &TextEntry) {
+    info!("Keyword: {}", entry.keyword);
+    if let TextKind::International { language, translated_keyword, .. } = &entry.kind {
+        info!("Language: {}", language);
+        info!("Translated keyword: {}", translated_keyword);
+    }
+    info!("Text: {}", entry.text);
+}
+
+// Decompresses the text of a zTXt or iTXt chunk, returning None if the compressed data is invalid
+fn decompress(compressed: &[u8], chunk_type: &str) -> Option<Vec<u8>> {
+    let mut text = Vec::new();
+    match zlib::read_zlib(SliceReader::new(compressed), &mut text, MAX_TEXT_SIZE, &[], |_| Ok(())) {
+        Ok(_) => Some(text),
+        Err(_) => {
+            warn!("Invalid compressed text in {} chunk", chunk_type);
+            None
+        },
+    }
+}
+
+// Checks that the chunk is long enough for its fixed fields before loading it, and ignores the chunk if it ends within a field
+fn load_text_chunk<R, F>(chunk: &mut ChunkReader<R>, length: u32, min_length: u32, chunk_type: &str, load: F) -> Result<Option<TextEntry>> where R: Read, F: FnOnce(&mut ChunkReader<R>) -> Result<Option<TextEntry>> {
+    if length < min_length {
+        warn!("{} chunk is too short", chunk_type);
+        return Ok(None);
+    }
+    match load(chunk) {
+        Err(Error::EndOfChunk(_)) => {
+            warn!("{} chunk ends within a field", chunk_type);
+            Ok(None)
+        },
+        Ok(Some(entry)) => {
+            print_entry(&entry);
+            Ok(Some(entry))
+        },
+        result => result,
+    }
+}
+
+// Returns None if the chunk is invalid, in which case it should be ignored
+pub fn load_text<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<TextEntry>> where R: Read {
+    // Keyword of at least one byte and its null separator
+    load_text_chunk(chunk, length, 2, "tEXt", |chunk| {
+        let keyword = match chunk.read_keyword()? {
+            Some(keyword) => keyword,
+            None => return Ok(None),
+        };
+        let text = chunk.read_rest()?;
+        if text.contains(&0) {
+            warn!("tEXt chunk contains a null character in the text");
+        }
+        Ok(Some(TextEntry { keyword, text: latin1(&text), kind: TextKind::Latin1 }))
+    })
+}
+
+pub fn load_ztxt<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<TextEntry>> where R: Read {
+    // Keyword, null separator and compression method
+    load_text_chunk(chunk, length, 3, "zTXt", |chunk| {
+        let keyword = match chunk.read_keyword()? {
+            Some(keyword) => keyword,
+            None => return Ok(None),
+        };
+        let compression_method = chunk.read_u8()?;
+        info!("Compression method: {}", compression_method);
+        if compression_method != 0 {
+            warn!("Unrecognized zTXt compression method");
+            return Ok(None);
+        }
+        let text = match decompress(&chunk.read_rest()?, "zTXt") {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        Ok(Some(TextEntry { keyword, text: latin1(&text), kind: TextKind::Compressed }))
+    })
+}
+
+pub fn load_itxt<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<TextEntry>> where R: Read {
+    // Keyword, null separator, compression flag and method, and the null separators after the language and translated keyword
+    load_text_chunk(chunk, length, 6, "iTXt", |chunk| {
+        let keyword = match chunk.read_keyword()? {
+            Some(keyword) => keyword,
+            None => return Ok(None),
+        };
+        let compression_flag = chunk.read_u8()?;
+        let compression_method = chunk.read_u8()?;
+        info!("Compression flag: {}", compression_flag);
+        info!("Compression method: {}", compression_method);
+        let compressed = match compression_flag {
+            0 => false,
+            1 => true,
+            _ => {
+                warn!("Invalid iTXt compression flag");
+                return Ok(None);
+            },
+        };
+        if compressed && compression_method != 0 {
+            warn!("Unrecognized iTXt compression method");
+            return Ok(None);
+        }
+        let language = chunk.read_null_terminated()?;
+        if !language.iter().all(|&b| b.is_ascii_alphanumeric() || b == b'-') {
+            warn!("iTXt language tag contains invalid characters");
+        }
+        let language = latin1(&language);
+        let translated_keyword = String::from_utf8_lossy(&chunk.read_null_terminated()?).into_owned();
+        let text = chunk.read_rest()?;
+        let text = if compressed {
+            match decompress(&text, "iTXt") {
+                Some(text) => text,
+                None => return Ok(None),
+            }
+        } else {
+            text
+        };
+        let text = match String::from_utf8(text) {
+            Ok(text) => text,
+            Err(err) => {
+                warn!("iTXt text is not valid UTF-8");
+                String::from_utf8_lossy(err.as_bytes()).into_owned()
+            },
+        };
+        Ok(Some(TextEntry { keyword, text, kind: TextKind::International { language, translated_keyword, compressed } }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::chunk_bytes;
+    use crate::chunk::CrcPolicy;
+    use crate::deflate;
+    use std::io::Cursor;
+
+    fn chunk(chunk_type: &[u8; 4], data: &[u8]) -> (ChunkReader<Cursor<Vec<u8>>>, u32) {
+        let (chunk, length, _) = ChunkReader::new(Cursor::new(chunk_bytes(chunk_type, data)), CrcPolicy::default()).unwrap();
+        (chunk, length)
+    }
+
+    #[test]
+    fn text_without_separator_is_ignored() {
+        let (mut chunk, length) = chunk(b"tEXt", b"Title");
+        assert!(load_text(&mut chunk, length).unwrap().is_none());
+        assert!(chunk.end().is_ok());
+    }
+
+    #[test]
+    fn ztxt_with_corrupt_stream_is_ignored() {
+        let (mut chunk, length) = chunk(b"zTXt", b"Title\0\0\x12\x34\x56");
+        assert!(load_ztxt(&mut chunk, length).unwrap().is_none());
+        assert!(chunk.end().is_ok());
+    }
+
+    #[test]
+    fn ztxt() {
+        for len in 0 .. 64 {
+            let text: String = (0 .. len).map(|i| (b'a' + (i * 7 % 26) as u8) as char).collect();
+            let mut data = b"Title\0\0".to_vec();
+            data.extend(deflate::write_zlib(text.as_bytes(), 6));
+            let (mut chunk, length) = chunk(b"zTXt", &data);
+            let entry = load_ztxt(&mut chunk, length).unwrap().unwrap();
+            assert_eq!(entry.keyword, "Title");
+            assert_eq!(entry.text, text);
+        }
+    }
+
+    #[test]
+    fn itxt_with_truncated_header_is_ignored() {
+        let (mut chunk, length) = chunk(b"iTXt", b"Title\0\0\0en");
+        assert!(load_itxt(&mut chunk, length).unwrap().is_none());
+        assert!(chunk.end().is_ok());
+    }
+}
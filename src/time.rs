@@ -0,0 +1,46 @@
+use crate::chunk::ChunkReader;
+use crate::file::ByteReader;
+use crate::Result;
+use std::fmt;
+use std::io::Read;
+
+// Time of the last modification of the image from a tIME chunk, in UTC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    // Up to 60 to allow for leap seconds
+    pub second: u8,
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// Returns None if the chunk is invalid, in which case it should be ignored
+pub fn load_time<R>(chunk: &mut ChunkReader<R>, length: u32) -> Result<Option<Timestamp>> where R: Read {
+    if length != 7 {
+        warn!("tIME chunk has incorrect length");
+        return Ok(None);
+    }
+    let timestamp = Timestamp {
+        year: chunk.read_u16()?,
+        month: chunk.read_u8()?,
+        day: chunk.read_u8()?,
+        hour: chunk.read_u8()?,
+        minute: chunk.read_u8()?,
+        second: chunk.read_u8()?,
+    };
+    info!("Modification time: {}", timestamp);
+    if !(1 ..= 12).contains(&timestamp.month) || !(1 ..= 31).contains(&timestamp.day)
+        || timestamp.hour > 23 || timestamp.minute > 59 || timestamp.second > 60 {
+        warn!("tIME chunk has out of range values");
+        return Ok(None);
+    }
+    Ok(Some(timestamp))
+}
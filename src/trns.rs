@@ -0,0 +1,120 @@
+use crate::chunk::ChunkReader;
+use crate::file::ByteReader;
+use crate::ihdr::ColorMode;
+use crate::Result;
+use std::io::Read;
+
+// Transparency information from a tRNS chunk.
+// Color keys are stored at the full bit depth of the image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transparency {
+    Palette(Box<[u8]>),
+    Gray(u16),
+    RGB(u16, u16, u16),
+}
+
+// Returns None if the chunk is not valid for the color mode, in which case it should be ignored
+pub fn load_trns<R>(chunk: &mut ChunkReader<R>, length: u32, color_mode: &ColorMode) -> Result<Option<Transparency>> where R: Read {
+    use ColorMode::*;
+    let max_sample = match color_mode {
+        Grayscale1 => 0x1,
+        Grayscale2 => 0x3,
+        Grayscale4 => 0xF,
+        Grayscale8 | RGB8 => 0xFF,
+        _ => 0xFFFF,
+    };
+    match color_mode {
+        Palette1(palette) | Palette2(palette) | Palette4(palette) | Palette8(palette) => {
+            if length as usize > palette.len() {
+                warn!("tRNS chunk has more entries than the palette");
+                return Ok(None);
+            }
+            let mut alphas = vec![0; length as usize].into_boxed_slice();
+            for v in alphas.iter_mut() {
+                *v = chunk.read_u8()?;
+            }
+            info!("Transparency: {:?}", alphas);
+            Ok(Some(Transparency::Palette(alphas)))
+        },
+        Grayscale1 | Grayscale2 | Grayscale4 | Grayscale8 | Grayscale16 => {
+            if length != 2 {
+                warn!("tRNS chunk has incorrect length for grayscale color");
+                return Ok(None);
+            }
+            let gray = chunk.read_u16()?;
+            info!("Transparent gray: {}", gray);
+            if gray > max_sample {
+                warn!("tRNS gray value exceeds bit depth");
+            }
+            Ok(Some(Transparency::Gray(gray)))
+        },
+        RGB8 | RGB16 => {
+            if length != 6 {
+                warn!("tRNS chunk has incorrect length for RGB color");
+                return Ok(None);
+            }
+            let red = chunk.read_u16()?;
+            let green = chunk.read_u16()?;
+            let blue = chunk.read_u16()?;
+            info!("Transparent color: ({}, {}, {})", red, green, blue);
+            if red > max_sample || green > max_sample || blue > max_sample {
+                warn!("tRNS color value exceeds bit depth");
+            }
+            Ok(Some(Transparency::RGB(red, green, blue)))
+        },
+        GrayscaleAlpha8 | GrayscaleAlpha16 | RGBA8 | RGBA16 => {
+            warn!("tRNS chunk with color type that has an alpha channel");
+            Ok(None)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::chunk_bytes;
+    use crate::Decoder;
+    use crate::Encoder;
+    use std::io::Cursor;
+
+    // Decodes a single row of samples with a tRNS chunk inserted before the IDAT chunk, returning the alpha of each pixel
+    fn alphas(color_mode: ColorMode, row: &[u8], width: u32, trns: &[u8]) -> Vec<u8> {
+        let png = Encoder::new(width, 1, color_mode).unwrap().encode(Vec::new(), row, row.len()).unwrap();
+        let idat_start = png.windows(4).position(|chunk_type| chunk_type == b"IDAT").unwrap() - 4;
+        let mut with_trns = png[.. idat_start].to_vec();
+        with_trns.extend(chunk_bytes(b"tRNS", trns));
+        with_trns.extend_from_slice(&png[idat_start ..]);
+        let mut pixels = vec![0; width as usize * 4];
+        Decoder::new(Cursor::new(with_trns)).unwrap().decode(&mut pixels, width as usize * 4).unwrap();
+        pixels.chunks(4).map(|pixel| pixel[3]).collect()
+    }
+
+    #[test]
+    fn gray_key_at_16_bits() {
+        // Both samples reduce to the same 8-bit value, but only the first matches the key
+        let row = [0x12, 0x34, 0x12, 0x35];
+        assert_eq!(alphas(ColorMode::Grayscale16, &row, 2, &[0x12, 0x34]), [0, 255]);
+    }
+
+    #[test]
+    fn rgb_key_at_16_bits() {
+        let row = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0x12, 0x34, 0x56, 0x78, 0x9A, 0xBD];
+        assert_eq!(alphas(ColorMode::RGB16, &row, 2, &[0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC]), [0, 255]);
+    }
+
+    #[test]
+    fn gray_keys_below_8_bits() {
+        assert_eq!(alphas(ColorMode::Grayscale1, &[0b0110_0000], 4, &[0, 1]), [255, 0, 0, 255]);
+        assert_eq!(alphas(ColorMode::Grayscale2, &[0b00_01_10_11], 4, &[0, 1]), [255, 0, 255, 255]);
+        assert_eq!(alphas(ColorMode::Grayscale4, &[0x0F, 0x50], 4, &[0, 5]), [255, 255, 0, 255]);
+        // The key is compared with the sample before scaling, so the scaled value 85 of sample 1 does not match
+        assert_eq!(alphas(ColorMode::Grayscale2, &[0b00_01_10_11], 4, &[0, 85]), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn palette_alphas() {
+        let palette = vec![(0, 0, 0), (1, 1, 1), (2, 2, 2)].into_boxed_slice();
+        // Entries past the end of the tRNS chunk are opaque
+        assert_eq!(alphas(ColorMode::Palette2(palette), &[0b00_01_10_00], 4, &[10, 20]), [10, 20, 255, 10]);
+    }
+}
@@ -1,48 +1,104 @@
 use crate::file::ByteReader;
 use crate::file::BitReader;
-use crate::idat::IdatReader;
 use crate::Error;
 use crate::Result;
 
-enum HuffmanCodes {
-    Value(u16),
-    Branch(Box<HuffmanCodes>, Box<HuffmanCodes>),
+// Number of bits used to index the primary decoding table.
+// Codes longer than this are decoded through a subtable selected by their first PRIMARY_BITS bits.
+const PRIMARY_BITS: u8 = 9;
+const MAX_CODE_LENGTH: u8 = 15;
+
+#[derive(Copy, Clone)]
+enum HuffmanEntry {
+    Value(u16, u8),
+    Subtable(usize, u8),
+}
+
+// Decoding table for a set of Huffman codes.
+// Since codes are packed starting from their most significant bit, tables are indexed by the bit-reversed codes.
+struct HuffmanCodes {
+    table: Box<[HuffmanEntry]>,
+}
+
+fn reverse_bits(code: u16, len: u8) -> usize {
+    (code.reverse_bits() >> (16 - len as u32)) as usize
 }
 
 impl HuffmanCodes {
     fn new(lengths: &mut [(u16, u16)]) -> Result<HuffmanCodes> {
-        fn rec(lengths: &[(u16, u16)], i: usize, depth: u16) -> Result<(HuffmanCodes, usize)> {
-            if i >= lengths.len() {
+        lengths.sort();
+        let lengths = &lengths[lengths.iter().take_while(|(l, _)| *l == 0).count() ..];
+        // Check that the codes are neither over-subscribed nor incomplete
+        let mut count = [0; MAX_CODE_LENGTH as usize + 1];
+        for &(len, _) in lengths {
+            if len > MAX_CODE_LENGTH as u16 {
                 return Err(Error::Format("Invalid Huffman codes"));
             }
-            let (len, val) = lengths[i]; // invariant: len >= depth
-            if len > depth {
-                let (codes_l, i1) = rec(lengths, i, depth + 1)?;
-                let (codes_r, i2) = rec(lengths, i1, depth + 1)?;
-                Ok((HuffmanCodes::Branch(Box::new(codes_l), Box::new(codes_r)), i2))
-            } else {
-                Ok((HuffmanCodes::Value(val), i + 1))
+            count[len as usize] += 1;
+        }
+        let mut left: i32 = 1;
+        for &len_count in &count[1 ..] {
+            left = (left << 1) - len_count;
+            if left < 0 {
+                return Err(Error::Format("Invalid Huffman codes"));
             }
         }
-        lengths.sort();
-        let i = lengths.iter().take_while(|(l, _)| *l == 0).count();
-        let (codes, i) = rec(lengths, i, 0)?;
-        if i < lengths.len() {
+        if left > 0 {
             return Err(Error::Format("Invalid Huffman codes"));
         }
-        Ok(codes)
+        // Assign canonical codes in order of length and value
+        let mut codes = Vec::with_capacity(lengths.len());
+        let mut code: u16 = 0;
+        let mut prev_len = 0;
+        for &(len, val) in lengths {
+            code <<= len - prev_len;
+            prev_len = len;
+            codes.push((reverse_bits(code, len as u8), len as u8, val));
+            code = code.wrapping_add(1);
+        }
+        // Allocate a subtable large enough for the longest code with each prefix
+        let primary_size = 1 << PRIMARY_BITS;
+        let mut subtable_bits = vec![0; primary_size];
+        for &(code, len, _) in &codes {
+            if len > PRIMARY_BITS {
+                let prefix = code & (primary_size - 1);
+                subtable_bits[prefix] = u8::max(subtable_bits[prefix], len - PRIMARY_BITS);
+            }
+        }
+        let mut table = vec![HuffmanEntry::Value(0, 0); primary_size];
+        for (prefix, &bits) in subtable_bits.iter().enumerate() {
+            if bits > 0 {
+                table[prefix] = HuffmanEntry::Subtable(table.len(), bits);
+                table.resize(table.len() + (1 << bits), HuffmanEntry::Value(0, 0));
+            }
+        }
+        for &(code, len, val) in &codes {
+            if len <= PRIMARY_BITS {
+                for i in (code .. primary_size).step_by(1 << len) {
+                    table[i] = HuffmanEntry::Value(val, len);
+                }
+            } else if let HuffmanEntry::Subtable(offset, bits) = table[code & (primary_size - 1)] {
+                for i in ((code >> PRIMARY_BITS) .. 1 << bits).step_by(1 << (len - PRIMARY_BITS)) {
+                    table[offset + i] = HuffmanEntry::Value(val, len);
+                }
+            }
+        }
+        Ok(HuffmanCodes { table: table.into_boxed_slice() })
     }
 }
 
-fn read_huffman<T>(reader: &mut BitReader<T>, mut codes: &HuffmanCodes) -> Result<u16> where T: ByteReader {
-    loop {
-        match codes {
-            HuffmanCodes::Value(val) => return Ok(*val),
-            HuffmanCodes::Branch(codes_l, codes_r) => codes = match reader.read_bit()? {
-                false => codes_l,
-                true => codes_r,
-            }
-        }
+fn read_huffman<T>(reader: &mut BitReader<T>, codes: &HuffmanCodes) -> Result<u16> where T: ByteReader {
+    let bits = reader.peek_bits(MAX_CODE_LENGTH)? as usize;
+    let entry = match codes.table[bits & ((1 << PRIMARY_BITS) - 1)] {
+        HuffmanEntry::Subtable(offset, len) => codes.table[offset + ((bits >> PRIMARY_BITS) & ((1 << len) - 1))],
+        entry => entry,
+    };
+    match entry {
+        HuffmanEntry::Value(val, len) => {
+            reader.consume_bits(len)?;
+            Ok(val)
+        },
+        HuffmanEntry::Subtable(_, _) => Err(Error::Format("Invalid Huffman codes")),
     }
 }
 
@@ -51,20 +107,21 @@ enum BlockType {
     Huffman(HuffmanCodes, HuffmanCodes),
 }
 
-const CODE_LENGTH_ORDER: [u16; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+pub const CODE_LENGTH_ORDER: [u16; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
 
-fn next_block(idat: &mut BitReader<IdatReader>) -> Result<(bool, BlockType)> {
-    println!("");
+fn next_block<T>(idat: &mut BitReader<T>) -> Result<(bool, BlockType)> where T: ByteReader {
+    info!("");
     let bfinal = idat.read_bit()?;
     let btype = idat.read_bits(2)?;
-    println!("Final block: {}", bfinal as u32);
-    println!("Block type: {}", btype);
+    info!("Final block: {}", bfinal as u32);
+    info!("Block type: {}", btype);
     let btype = match btype {
         0 => {
-            let len = idat.read_u16()?;
-            println!("Block length: {}", len);
-            let nlen = idat.read_u16()?;
-            println!("One's complement of block's length: {}", nlen);
+            idat.align();
+            let len = idat.read_bits(16)? as u16;
+            info!("Block length: {}", len);
+            let nlen = idat.read_bits(16)? as u16;
+            info!("One's complement of block's length: {}", nlen);
             if !len != nlen {
                 warn!("One's complement of block length is incorrect");
             }
@@ -83,14 +140,14 @@ fn next_block(idat: &mut BitReader<IdatReader>) -> Result<(bool, BlockType)> {
         },
         2 => {
             let literals_num = (idat.read_bits(5)? as usize) + 257;
-            println!("Number of literal/length codes: {}", literals_num);
+            info!("Number of literal/length codes: {}", literals_num);
             let distances_num = (idat.read_bits(5)? as usize) + 1;
-            println!("Number of distance codes: {}", distances_num);
+            info!("Number of distance codes: {}", distances_num);
             let code_lengths_num = (idat.read_bits(4)? as usize) + 4;
-            println!("Number of code length codes: {}", code_lengths_num);
+            info!("Number of code length codes: {}", code_lengths_num);
             let mut code_lengths_lengths = vec![(0, 0); code_lengths_num].into_boxed_slice();
             for (i, v) in code_lengths_lengths.iter_mut().enumerate() {
-                *v = (idat.read_bits(3)?, CODE_LENGTH_ORDER[i]);
+                *v = (idat.read_bits(3)? as u16, CODE_LENGTH_ORDER[i]);
             }
             let code_lengths_codes = HuffmanCodes::new(&mut code_lengths_lengths)?;
             let mut literals_distances_lengths = vec![(0, 0); literals_num + distances_num].into_boxed_slice();
@@ -100,9 +157,9 @@ fn next_block(idat: &mut BitReader<IdatReader>) -> Result<(bool, BlockType)> {
                 for (i, v) in literals_distances_lengths.iter_mut().enumerate() {
                     if repeats == 0 {
                         let repeat = match read_huffman(idat, &code_lengths_codes)? {
-                            16 => (repeated, idat.read_bits(2)? + 3),
-                            17 => (Some(0), idat.read_bits(3)? + 3),
-                            18 => (Some(0), idat.read_bits(7)? + 11),
+                            16 => (repeated, idat.read_bits(2)? as u16 + 3),
+                            17 => (Some(0), idat.read_bits(3)? as u16 + 3),
+                            18 => (Some(0), idat.read_bits(7)? as u16 + 11),
                             val => (Some(val), 1),
                         };
                         repeated = repeat.0;
@@ -128,7 +185,7 @@ fn next_block(idat: &mut BitReader<IdatReader>) -> Result<(bool, BlockType)> {
     Ok((bfinal, btype))
 }
 
-const length_code_interpretation: [(usize, u8); 29] = [
+pub const LENGTH_CODE_INTERPRETATION: [(usize, u8); 29] = [
     (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 0),
     (11, 1), (13, 1), (15, 1), (17, 1),
     (19, 2), (23, 2), (27, 2), (31, 2),
@@ -138,7 +195,7 @@ const length_code_interpretation: [(usize, u8); 29] = [
     (258, 0),
 ];
 
-const distance_code_interpretation: [(usize, u8); 30] = [
+pub const DISTANCE_CODE_INTERPRETATION: [(usize, u8); 30] = [
     (1, 0), (2, 0), (3, 0), (4, 0),
     (5, 1), (7, 1), (9, 2), (13, 2),
     (17, 3), (25, 3), (33, 4), (49, 4),
@@ -149,71 +206,128 @@ const distance_code_interpretation: [(usize, u8); 30] = [
     (16385, 13), (24577, 13),
 ];
 
-pub fn read_zlib(mut idat: IdatReader, buf: &mut [u8]) -> Result<IdatReader> {
-    let cmf = idat.read_u8()?;
-    println!("Compression method: {}", cmf & 0xF);
+const ADLER_MOD: u32 = 65521;
+// Largest multiple of 16 bytes that can be summed before the sums have to be reduced modulo ADLER_MOD
+const ADLER_BLOCK: usize = 5552;
+
+// Computes the Adler-32 checksum of the data.
+// Blocks are summed in 16 independent lanes, which lets the compiler vectorise the inner loop.
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1;
+    let mut b = 0;
+    for block in data.chunks(ADLER_BLOCK) {
+        let mut lanes_a = [0u32; 16];
+        let mut lanes_b = [0u32; 16];
+        let chunks = block.chunks_exact(16);
+        let rest = chunks.remainder();
+        for chunk in chunks {
+            for k in 0 .. 16 {
+                lanes_b[k] += lanes_a[k];
+                lanes_a[k] += chunk[k] as u32;
+            }
+        }
+        // Byte k of the j-th of n chunks is added to b (16 * (n - 1 - j) + 16 - k) times
+        let len = (block.len() - rest.len()) as u64;
+        let mut sum_a = 0;
+        let mut sum_b = b as u64 + len * a as u64;
+        for k in 0 .. 16 {
+            sum_a += lanes_a[k] as u64;
+            sum_b += 16 * lanes_b[k] as u64 + (16 - k as u64) * lanes_a[k] as u64;
+        }
+        a = ((a as u64 + sum_a) % ADLER_MOD as u64) as u32;
+        b = (sum_b % ADLER_MOD as u64) as u32;
+        for &byte in rest {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+    (b << 16) | a
+}
+
+// Calls the callback with the data decompressed so far whenever the next checkpoint is reached.
+// Checkpoints are offsets in the buffer and have to be sorted.
+fn reach_checkpoints<F>(buf: &[u8], checkpoints: &mut &[usize], on_checkpoint: &mut F) -> Result<()> where F: FnMut(&[u8]) -> Result<()> {
+    while let Some((&checkpoint, rest)) = checkpoints.split_first() {
+        if checkpoint > buf.len() {
+            break;
+        }
+        on_checkpoint(&buf[.. checkpoint])?;
+        *checkpoints = rest;
+    }
+    Ok(())
+}
+
+// Decompresses a zlib stream, appending at most max_len bytes to the buffer
+pub fn read_zlib<T, F>(mut reader: T, buf: &mut Vec<u8>, max_len: usize, mut checkpoints: &[usize], mut on_checkpoint: F) -> Result<T> where T: ByteReader, F: FnMut(&[u8]) -> Result<()> {
+    let cmf = reader.read_u8()?;
+    info!("Compression method: {}", cmf & 0xF);
     if cmf & 0xF != 0x8 {
         return Err(Error::Format("Unrecognized compression method"));
     }
-    println!("Compression window size: {}", 1 << ((cmf >> 4) as u32 + 8));
+    info!("Compression window size: {}", 1 << ((cmf >> 4) as u32 + 8));
     if cmf >> 4 > 7 {
         warn!("Compression window size above 32K");
     }
-    let flags = idat.read_u8()?;
-    println!("Check bits: {:02X}", flags & 0x1F);
-    if (((cmf as u16) << 8) + flags as u16) % 31 != 0 {
+    let flags = reader.read_u8()?;
+    info!("Check bits: {:02X}", flags & 0x1F);
+    if !(((cmf as u16) << 8) + flags as u16).is_multiple_of(31) {
         warn!("Check bits are incorrect");
     }
-    println!("Preset dictionary: {}", (flags & 0x20) >> 5);
+    info!("Preset dictionary: {}", (flags & 0x20) >> 5);
     if flags & 0x20 != 0 {
         return Err(Error::Format("Preset dictionary set"));
     }
-    println!("Compression level: {}", flags >> 6);
-    let mut idat = BitReader::new(idat);
-    let mut i = 0;
+    info!("Compression level: {}", flags >> 6);
+    let mut reader = BitReader::new(reader);
+    let start = buf.len();
+    let max_len = start + max_len;
+    reach_checkpoints(buf, &mut checkpoints, &mut on_checkpoint)?;
     loop {
-        let (block_final, block_type) = next_block(&mut idat)?;
+        let (block_final, block_type) = next_block(&mut reader)?;
         match block_type {
             BlockType::Uncompressed(len) => {
-                for _ in 0 .. len {
-                    if i >= buf.len() {
-                        return Err(Error::Format("Too much image data"));
-                    }
-                    buf[i] = idat.read_u8()?;
-                    i += 1;
+                let len = len as usize;
+                let i = buf.len();
+                if i + len > max_len {
+                    return Err(Error::Format("Too much decompressed data"));
                 }
+                buf.resize(i + len, 0);
+                reader.read_buf(&mut buf[i ..])?;
+                reach_checkpoints(buf, &mut checkpoints, &mut on_checkpoint)?;
             },
             BlockType::Huffman(literal_codes, distance_codes) => {
                 loop {
-                    let val = read_huffman(&mut idat, &literal_codes)?;
+                    let val = read_huffman(&mut reader, &literal_codes)?;
                     match val {
                         0 ..= 255 => {
-                            if i >= buf.len() {
-                                return Err(Error::Format("Too much image data"));
+                            if buf.len() >= max_len {
+                                return Err(Error::Format("Too much decompressed data"));
                             }
-                            buf[i] = val as u8;
-                            i += 1;
+                            buf.push(val as u8);
+                            reach_checkpoints(buf, &mut checkpoints, &mut on_checkpoint)?;
                         },
                         256 => break,
                         257 ..= 285 => {
-                            let (base_length, length_extra_bits) = length_code_interpretation[(val - 257) as usize];
-                            let length = base_length + idat.read_bits(length_extra_bits)? as usize;
-                            let distance_code = read_huffman(&mut idat, &distance_codes)?;
+                            let (base_length, length_extra_bits) = LENGTH_CODE_INTERPRETATION[(val - 257) as usize];
+                            let length = base_length + reader.read_bits(length_extra_bits)? as usize;
+                            let distance_code = read_huffman(&mut reader, &distance_codes)?;
                             if distance_code > 29 {
                                 return Err(Error::Format("A distance code of 30-31 occured in the compressed data"));
                             }
-                            let (base_distance, distance_extra_bits) = distance_code_interpretation[distance_code as usize];
-                            let distance = base_distance + idat.read_bits(distance_extra_bits)? as usize;
-                            if distance > i {
+                            let (base_distance, distance_extra_bits) = DISTANCE_CODE_INTERPRETATION[distance_code as usize];
+                            let distance = base_distance + reader.read_bits(distance_extra_bits)? as usize;
+                            if distance > buf.len() - start {
                                 return Err(Error::Format("Distance refers past the beginning of the output"));
                             }
+                            if buf.len() + length > max_len {
+                                return Err(Error::Format("Too much decompressed data"));
+                            }
                             for _ in 0 .. length {
-                                if i >= buf.len() {
-                                    return Err(Error::Format("Too much image data"));
-                                }
-                                buf[i] = buf[i - distance];
-                                i += 1;
+                                buf.push(buf[buf.len() - distance]);
                             }
+                            reach_checkpoints(buf, &mut checkpoints, &mut on_checkpoint)?;
                         },
                         _ => return Err(Error::Format("A value of 286-287 occured in the compressed data")),
                     }
@@ -224,10 +338,99 @@ pub fn read_zlib(mut idat: IdatReader, buf: &mut [u8]) -> Result<IdatReader> {
             break;
         }
     }
-    if i < buf.len() {
-        return Err(Error::Format("Not enough image data"));
+    let checksum = reader.read_u32()?;
+    info!("Adler-32 checksum: {:08X}", checksum);
+    let adler = adler32(&buf[start ..]);
+    if checksum != adler {
+        return Err(Error::Checksum(format!("Adler-32 mismatch (expected {:08X}, got {:08X})", checksum, adler)));
+    }
+    Ok(reader.end())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate;
+    use crate::file::SliceReader;
+
+    // Computes the Adler-32 checksum one byte at a time, reducing after every byte
+    fn adler32_bytewise(data: &[u8]) -> u32 {
+        let mut a = 1;
+        let mut b = 0;
+        for &byte in data {
+            a = (a + byte as u32) % ADLER_MOD;
+            b = (b + a) % ADLER_MOD;
+        }
+        (b << 16) | a
+    }
+
+    #[test]
+    fn adler32_known_value() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn adler32_sums_do_not_overflow() {
+        for &len in &[ADLER_BLOCK - 1, ADLER_BLOCK, ADLER_BLOCK + 1, 3 * ADLER_BLOCK + 17, 100_000] {
+            let data = vec![0xFF; len];
+            assert_eq!(adler32(&data), adler32_bytewise(&data), "length {}", len);
+        }
+    }
+
+    #[test]
+    fn adler32_mismatch() {
+        let data = b"The quick brown fox jumps over the lazy dog".repeat(10);
+        let mut compressed = deflate::write_zlib(&data, 6);
+        *compressed.last_mut().unwrap() ^= 0x01;
+        let mut buf = Vec::new();
+        match read_zlib(SliceReader::new(&compressed), &mut buf, data.len(), &[], |_| Ok(())) {
+            Err(Error::Checksum(message)) => assert!(message.starts_with("Adler-32 mismatch"), "{}", message),
+            _ => panic!("Expected an Adler-32 mismatch"),
+        }
+    }
+
+    // Packs codes starting from their most significant bit, as Huffman codes are stored
+    fn pack_codes(codes: &[(u16, u8)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut bits = 0;
+        for &(code, len) in codes {
+            for i in (0 .. len).rev() {
+                if bits % 8 == 0 {
+                    bytes.push(0);
+                }
+                *bytes.last_mut().unwrap() |= (((code >> i) & 1) as u8) << (bits % 8);
+                bits += 1;
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn over_subscribed_codes() {
+        assert!(HuffmanCodes::new(&mut [(1, 0), (1, 1), (1, 2)]).is_err());
+        assert!(HuffmanCodes::new(&mut [(1, 0), (2, 1), (2, 2), (2, 3)]).is_err());
+    }
+
+    #[test]
+    fn incomplete_codes() {
+        assert!(HuffmanCodes::new(&mut [(1, 0), (2, 1)]).is_err());
+        assert!(HuffmanCodes::new(&mut [(0, 0), (3, 1), (2, 3), (1, 4), (0, 5)]).is_err());
+    }
+
+    #[test]
+    fn codes_longer_than_primary_table() {
+        // Value i < 15 has a code of i ones followed by a zero, and value 15 has a code of 15 ones
+        let mut lengths: Vec<(u16, u16)> = (0 .. 16).map(|i| (u16::min(i + 1, MAX_CODE_LENGTH as u16), i)).collect();
+        let codes = HuffmanCodes::new(&mut lengths).unwrap();
+        let code = |val: u16| if val == 15 { (0x7FFF, 15) } else { (((1 << val) - 1) << 1, val as u8 + 1) };
+        let values = [15, 0, 14, 9, 10, 3, 12, 15, 1, 13, 8];
+        assert!(values.iter().any(|&val| code(val).1 > PRIMARY_BITS));
+        let data = pack_codes(&values.iter().map(|&val| code(val)).collect::<Vec<_>>());
+        let mut reader = BitReader::new(SliceReader::new(&data));
+        for &val in &values {
+            assert_eq!(read_huffman(&mut reader, &codes).unwrap(), val);
+        }
     }
-    // TODO checksum
-    idat.read_u32()?;
-    Ok(idat.end())
 }